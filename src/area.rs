@@ -0,0 +1,483 @@
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io::BufReader;
+use serde::Deserialize;
+use crate::{CanvasLine, LineCoordinate, TileCircle, TileLocation, TileRegion};
+
+/**
+ * Shape of a search area as given in the configuration
+ */
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum AreaConfig {
+    Region(TileRegion),
+    Polygon {
+        polygon: Vec<[i16; 2]>,
+    },
+    Mask {
+        mask: String,
+        left: i16,
+        top: i16,
+    },
+    Template {
+        template: String,
+        left: i16,
+        top: i16,
+    },
+}
+
+/**
+ * Shape of a search area, polygons and image masks are converted to tile masks when
+ * the configuration is read, templates also require edits to match the colour of the image
+ */
+#[derive(Deserialize, Clone)]
+#[serde(try_from = "AreaConfig")]
+pub(crate) enum AreaShape {
+    Region(TileRegion),
+    Mask(TileMask),
+    Template(TileTemplate),
+}
+
+impl TryFrom<AreaConfig> for AreaShape {
+    type Error = String;
+
+    fn try_from(value: AreaConfig) -> Result<Self, Self::Error> {
+        match value {
+            AreaConfig::Region(r) => { Ok(AreaShape::Region(r)) }
+            AreaConfig::Polygon { polygon } => {
+                let vertices: Vec<TileLocation> = polygon.iter().map(|[x, y]| TileLocation {
+                    x: *x,
+                    y: *y,
+                }).collect();
+                Ok(AreaShape::Mask(TileMask::from_polygon(&vertices)?))
+            }
+            AreaConfig::Mask { mask, left, top } => {
+                let image = Image::load(&mask)?;
+                Ok(AreaShape::Mask(TileMask::from_image(&image, left, top)))
+            }
+            AreaConfig::Template { template, left, top } => {
+                let image = Image::load(&template)?;
+                Ok(AreaShape::Template(TileTemplate::new(image, left, top)))
+            }
+        }
+    }
+}
+
+impl Display for AreaShape {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AreaShape::Region(r) => { write!(f, "{}", r) }
+            AreaShape::Mask(m) => { write!(f, "mask in {}", m.bounds) }
+            AreaShape::Template(t) => { write!(f, "template in {}", t.mask.bounds) }
+        }
+    }
+}
+
+impl AreaShape {
+    pub(crate) fn contains(&self, location: &TileLocation) -> bool {
+        match self {
+            AreaShape::Region(r) => { r.contains(location) }
+            AreaShape::Mask(m) => { m.contains_point(location.x, location.y) }
+            AreaShape::Template(t) => { t.mask.contains_point(location.x, location.y) }
+        }
+    }
+
+    pub(crate) fn intersects(&self, region: &TileRegion) -> bool {
+        match self {
+            AreaShape::Region(r) => { r.intersects(region) }
+            AreaShape::Mask(m) => { m.intersects(region) }
+            AreaShape::Template(t) => { t.mask.intersects(region) }
+        }
+    }
+
+    pub(crate) fn intersects_circle(&self, circle: &TileCircle) -> bool {
+        match self {
+            AreaShape::Region(r) => { circle.intersects(r) }
+            AreaShape::Mask(m) => { m.intersects_circle(circle) }
+            AreaShape::Template(t) => { t.mask.intersects_circle(circle) }
+        }
+    }
+
+    /**
+     * Smallest region containing every tile of the area
+     */
+    pub(crate) fn bounds(&self) -> &TileRegion {
+        match self {
+            AreaShape::Region(r) => { r }
+            AreaShape::Mask(m) => { &m.bounds }
+            AreaShape::Template(t) => { &t.mask.bounds }
+        }
+    }
+
+    /**
+     * Check if the edit changes any tile in the area
+     */
+    pub(crate) fn contains_edit(&self, coordinate: &LineCoordinate) -> bool {
+        match coordinate {
+            LineCoordinate::Tile(t) => { self.contains(t) }
+            LineCoordinate::Region(r) => { self.intersects(r) }
+            LineCoordinate::Circle(c) => { self.intersects_circle(c) }
+        }
+    }
+
+    /**
+     * Check if the edit changes any tile in the area, for templates the colour of the edit
+     * also has to match the template at one of the changed tiles
+     */
+    pub(crate) fn matches_edit(&self, pixel: &CanvasLine) -> bool {
+        match self {
+            AreaShape::Template(t) => { t.matches_edit(pixel) }
+            _ => { self.contains_edit(&pixel.coordinate) }
+        }
+    }
+}
+
+/**
+ * Decoded image with the colour of each opaque pixel
+ */
+pub(crate) struct Image {
+    pub(crate) width: usize,
+    pub(crate) height: usize,
+    pub(crate) pixels: Vec<Option<[u8; 3]>>,
+}
+
+impl Image {
+    /**
+     * Load PNG image, pixels that are not fully transparent are opaque
+     */
+    pub(crate) fn load(path: &str) -> Result<Image, String> {
+        let file = File::open(path)
+            .map_err(|e| format!("Failed to open image {}: {}", path, e))?;
+        let mut decoder = png::Decoder::new(BufReader::new(file));
+        decoder.set_transformations(png::Transformations::normalize_to_color8());
+        let mut reader = decoder.read_info()
+            .map_err(|e| format!("Failed to read image {}: {}", path, e))?;
+        let mut buffer = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut buffer)
+            .map_err(|e| format!("Failed to decode image {}: {}", path, e))?;
+        let data = &buffer[..info.buffer_size()];
+
+        let pixels = match info.color_type {
+            png::ColorType::Rgba => {
+                data.chunks_exact(4).map(|p| (p[3] != 0).then_some([p[0], p[1], p[2]])).collect()
+            }
+            png::ColorType::Rgb => {
+                data.chunks_exact(3).map(|p| Some([p[0], p[1], p[2]])).collect()
+            }
+            png::ColorType::GrayscaleAlpha => {
+                data.chunks_exact(2).map(|p| (p[1] != 0).then_some([p[0], p[0], p[0]])).collect()
+            }
+            png::ColorType::Grayscale => {
+                data.iter().map(|p| Some([*p, *p, *p])).collect()
+            }
+            png::ColorType::Indexed => {
+                return Err(format!("Unsupported colour type in image {}", path));
+            }
+        };
+        Ok(Image {
+            width: info.width as usize,
+            height: info.height as usize,
+            pixels,
+        })
+    }
+}
+
+/**
+ * Set of tiles inside a bounding region
+ */
+#[derive(Clone)]
+pub(crate) struct TileMask {
+    bounds: TileRegion,
+    tiles: Vec<bool>,
+}
+
+impl TileMask {
+    fn empty(bounds: TileRegion) -> TileMask {
+        let width = (bounds.right as i32 - bounds.left as i32 + 1).max(0) as usize;
+        let height = (bounds.bottom as i32 - bounds.top as i32 + 1).max(0) as usize;
+        TileMask {
+            bounds,
+            tiles: vec![false; width * height],
+        }
+    }
+
+    fn index(&self, x: i16, y: i16) -> Option<usize> {
+        if !self.bounds.contains_point(x, y) {
+            return None;
+        }
+        let width = (self.bounds.right as i32 - self.bounds.left as i32 + 1) as usize;
+        Some((y as i32 - self.bounds.top as i32) as usize * width + (x as i32 - self.bounds.left as i32) as usize)
+    }
+
+    /**
+     * Create mask of the opaque pixels of the image, image is placed with its top left pixel at the given tile
+     */
+    pub(crate) fn from_image(image: &Image, left: i16, top: i16) -> TileMask {
+        let bounds = image_bounds(image, left, top);
+        let mut mask = TileMask::empty(bounds);
+        for (i, pixel) in image.pixels.iter().enumerate() {
+            if pixel.is_some() {
+                let x = left as i32 + (i % image.width) as i32;
+                let y = top as i32 + (i / image.width) as i32;
+                if let (Ok(x), Ok(y)) = (i16::try_from(x), i16::try_from(y)) {
+                    mask.set(x, y);
+                }
+            }
+        }
+        mask
+    }
+
+    /**
+     * Create mask of the tiles inside the polygon, tiles on the edges are inside
+     */
+    pub(crate) fn from_polygon(vertices: &[TileLocation]) -> Result<TileMask, String> {
+        if vertices.len() < 3 {
+            return Err("Polygon needs at least three vertices".to_string());
+        }
+        let bounds = TileRegion {
+            left: vertices.iter().map(|v| v.x).min().unwrap_or(0),
+            top: vertices.iter().map(|v| v.y).min().unwrap_or(0),
+            right: vertices.iter().map(|v| v.x).max().unwrap_or(0),
+            bottom: vertices.iter().map(|v| v.y).max().unwrap_or(0),
+        };
+        let mut mask = TileMask::empty(bounds.clone());
+        for tile in bounds.points() {
+            if polygon_contains(vertices, tile.x, tile.y) {
+                mask.set(tile.x, tile.y);
+            }
+        }
+        Ok(mask)
+    }
+
+    fn set(&mut self, x: i16, y: i16) {
+        if let Some(i) = self.index(x, y) {
+            self.tiles[i] = true;
+        }
+    }
+
+    pub(crate) fn contains_point(&self, x: i16, y: i16) -> bool {
+        self.index(x, y).is_some_and(|i| self.tiles[i])
+    }
+
+    pub(crate) fn intersects(&self, region: &TileRegion) -> bool {
+        if !self.bounds.intersects(region) {
+            return false;
+        }
+        let overlap = TileRegion {
+            left: self.bounds.left.max(region.left),
+            top: self.bounds.top.max(region.top),
+            right: self.bounds.right.min(region.right),
+            bottom: self.bounds.bottom.min(region.bottom),
+        };
+        overlap.points().any(|t| self.contains_point(t.x, t.y))
+    }
+
+    pub(crate) fn intersects_circle(&self, circle: &TileCircle) -> bool {
+        circle.intersects(&self.bounds)
+            && circle.points().any(|t| self.contains_point(t.x, t.y))
+    }
+}
+
+/**
+ * Image placed on the canvas, edits match the template when they have the colour of the image
+ * at the edited tile
+ */
+#[derive(Clone)]
+pub(crate) struct TileTemplate {
+    mask: TileMask,
+    left: i16,
+    top: i16,
+    width: usize,
+    colours: Vec<Option<[u8; 3]>>,
+}
+
+impl TileTemplate {
+    pub(crate) fn new(image: Image, left: i16, top: i16) -> TileTemplate {
+        TileTemplate {
+            mask: TileMask::from_image(&image, left, top),
+            left,
+            top,
+            width: image.width,
+            colours: image.pixels,
+        }
+    }
+
+    /**
+     * Colour of the template at the tile, None for tiles outside of the template
+     */
+    pub(crate) fn colour_at(&self, x: i16, y: i16) -> Option<[u8; 3]> {
+        if !self.mask.contains_point(x, y) {
+            return None;
+        }
+        let column = (x as i32 - self.left as i32) as usize;
+        let row = (y as i32 - self.top as i32) as usize;
+        self.colours[row * self.width + column]
+    }
+
+    pub(crate) fn matches_edit(&self, pixel: &CanvasLine) -> bool {
+        let colour = pixel.pixel_color;
+        match &pixel.coordinate {
+            LineCoordinate::Tile(t) => { self.colour_at(t.x, t.y) == Some(colour) }
+            LineCoordinate::Region(r) => {
+                self.mask.intersects(r)
+                    && r.points().any(|t| self.colour_at(t.x, t.y) == Some(colour))
+            }
+            LineCoordinate::Circle(c) => {
+                self.mask.intersects_circle(c)
+                    && c.points().any(|t| self.colour_at(t.x, t.y) == Some(colour))
+            }
+        }
+    }
+}
+
+/**
+ * Parse colour in the hex format of the canvas history, like #FF4500
+ */
+pub(crate) fn parse_colour(colour: &str) -> Option<[u8; 3]> {
+    let hex = colour.strip_prefix('#')?;
+    if hex.len() != 6 {
+        return None;
+    }
+    let channel = |i: usize| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok();
+    Some([channel(0)?, channel(2)?, channel(4)?])
+}
+
+/**
+ * Region of the canvas covered by the image placed at the given tile
+ */
+pub(crate) fn image_bounds(image: &Image, left: i16, top: i16) -> TileRegion {
+    let clamp = |v: i32| v.clamp(i16::MIN as i32, i16::MAX as i32) as i16;
+    TileRegion {
+        left,
+        top,
+        right: clamp(left as i32 + image.width as i32 - 1),
+        bottom: clamp(top as i32 + image.height as i32 - 1),
+    }
+}
+
+/**
+ * Check if the tile is inside the polygon or on its edges
+ */
+fn polygon_contains(vertices: &[TileLocation], x: i16, y: i16) -> bool {
+    let (px, py) = (x as i64, y as i64);
+    let mut inside = false;
+    for (i, a) in vertices.iter().enumerate() {
+        let b = &vertices[(i + 1) % vertices.len()];
+        let (ax, ay, bx, by) = (a.x as i64, a.y as i64, b.x as i64, b.y as i64);
+
+        //Tiles on the edge are inside
+        let cross = (bx - ax) * (py - ay) - (by - ay) * (px - ax);
+        if cross == 0 && px >= ax.min(bx) && px <= ax.max(bx) && py >= ay.min(by) && py <= ay.max(by) {
+            return true;
+        }
+
+        //Count crossings of a ray going right from the tile
+        if (ay > py) != (by > py) {
+            //x coordinate of the crossing compared without division
+            let lhs = (px - ax) * (by - ay);
+            let rhs = (bx - ax) * (py - ay);
+            if (by > ay && lhs < rhs) || (by < ay && lhs > rhs) {
+                inside = !inside;
+            }
+        }
+    }
+    inside
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vertices(points: &[(i16, i16)]) -> Vec<TileLocation> {
+        points.iter().map(|(x, y)| TileLocation {
+            x: *x,
+            y: *y,
+        }).collect()
+    }
+
+    #[test]
+    fn rectangle_polygon_matches_region() {
+        let region = TileRegion {
+            left: -3,
+            top: 2,
+            right: 5,
+            bottom: 9,
+        };
+        let mask = TileMask::from_polygon(&vertices(&[(-3, 2), (5, 2), (5, 9), (-3, 9)]))
+            .expect("Valid polygon");
+        for x in -6..8 {
+            for y in -1..12 {
+                assert_eq!(mask.contains_point(x, y), region.contains_point(x, y), "{}, {}", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn triangle_polygon() {
+        let mask = TileMask::from_polygon(&vertices(&[(0, 0), (10, 0), (0, 10)]))
+            .expect("Valid polygon");
+        assert!(mask.contains_point(0, 0));
+        assert!(mask.contains_point(5, 5));
+        assert!(mask.contains_point(2, 3));
+        assert!(!mask.contains_point(6, 5));
+        assert!(!mask.contains_point(10, 10));
+        assert!(mask.intersects(&TileRegion {
+            left: 4,
+            top: 4,
+            right: 20,
+            bottom: 20,
+        }));
+        assert!(!mask.intersects(&TileRegion {
+            left: 6,
+            top: 6,
+            right: 20,
+            bottom: 20,
+        }));
+        assert!(mask.intersects_circle(&TileCircle {
+            x: 8,
+            y: 8,
+            r: 5,
+        }));
+        assert!(!mask.intersects_circle(&TileCircle {
+            x: 9,
+            y: 9,
+            r: 3,
+        }));
+    }
+
+    #[test]
+    fn template_matches_colour_of_tile() {
+        let red = Some([255, 0, 0]);
+        let image = Image {
+            width: 2,
+            height: 2,
+            pixels: vec![red, None, Some([0, 0, 0]), red],
+        };
+        let template = TileTemplate::new(image, 10, 20);
+        let edit = |colour: &str, coordinate: LineCoordinate| CanvasLine {
+            timestamp: crate::parse_timestamp("2022-04-01 12:00:00.000 UTC").expect("Valid time"),
+            user_id: crate::users::UserId(0),
+            pixel_color: parse_colour(colour).expect("Valid colour"),
+            coordinate,
+        };
+        let tile = |x, y| LineCoordinate::Tile(TileLocation { x, y });
+
+        assert!(template.matches_edit(&edit("#FF0000", tile(10, 20))));
+        assert!(template.matches_edit(&edit("#FF0000", tile(11, 21))));
+        assert!(template.matches_edit(&edit("#000000", tile(10, 21))));
+        assert!(!template.matches_edit(&edit("#000000", tile(10, 20))));
+        assert!(!template.matches_edit(&edit("#FF0000", tile(11, 20))));
+        assert!(!template.matches_edit(&edit("#FF0000", tile(12, 20))));
+        assert!(template.matches_edit(&edit("#000000", LineCoordinate::Region(TileRegion {
+            left: 0,
+            top: 0,
+            right: 10,
+            bottom: 21,
+        }))));
+    }
+
+    #[test]
+    fn polygon_needs_three_vertices() {
+        assert!(TileMask::from_polygon(&vertices(&[(0, 0), (10, 0)])).is_err());
+    }
+}
@@ -0,0 +1,568 @@
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use log::{info, warn};
+use time::PrimitiveDateTime;
+use crate::{CanvasLine, from_millis, LineCoordinate, PlaceDataSet, TileCircle, TileLocation, TileRegion, to_millis};
+use crate::area::parse_colour;
+use crate::canvas::format_colour;
+use crate::input::{check_file_boundary, parse_row, tile_data_lines};
+use crate::users::UserId;
+use crate::users::UserTable;
+
+/*
+ * Layout of the cache file, all numbers are little endian
+ *
+ * magic            8 bytes
+ * data set         u8
+ * source files     u16 count, canonical paths as u16 length and bytes followed by file size as u64
+ * row count        u64
+ * base time        i64, milliseconds since unix epoch of the first row
+ * palette          u16 count, colours as u8 length and bytes
+ * users            u32 count, user ids as u16 length and bytes
+ * non-tile rows    u32 count, row index as u64, kind as u8 and four i16 values
+ * time column      i32 per row, milliseconds from base time
+ * user column      u32 per row, index to the user table
+ * colour column    u8 per row, index to the palette
+ * x column         i16 per row
+ * y column         i16 per row
+ *
+ * Rows that are regions or circles have zero in x and y columns and
+ * their coordinates are stored in the non-tile rows table
+ */
+const CACHE_MAGIC: &[u8; 8] = b"RPLACE02";
+const KIND_REGION: u8 = 1;
+const KIND_CIRCLE: u8 = 2;
+
+/**
+ * Coordinates of a row that is not a single tile
+ */
+struct ExtraCoordinate {
+    row: u64,
+    kind: u8,
+    values: [i16; 4],
+}
+
+fn dataset_code(year: &PlaceDataSet) -> u8 {
+    match year {
+        PlaceDataSet::Place2022 => { 0 }
+        PlaceDataSet::Place2023 => { 1 }
+        PlaceDataSet::Place2017 => { 2 }
+    }
+}
+
+fn dataset_from_code(code: u8) -> io::Result<PlaceDataSet> {
+    match code {
+        0 => { Ok(PlaceDataSet::Place2022) }
+        1 => { Ok(PlaceDataSet::Place2023) }
+        2 => { Ok(PlaceDataSet::Place2017) }
+        _ => { Err(io::Error::new(io::ErrorKind::InvalidData, "Unknown data set in cache")) }
+    }
+}
+
+/**
+ * Canonical path and size of a source file, used to check the cache was created from the same files
+ */
+fn source_file(path: &Path) -> io::Result<(String, u64)> {
+    let canonical = fs::canonicalize(path)?;
+    let size = fs::metadata(&canonical)?.len();
+    Ok((canonical.to_string_lossy().into_owned(), size))
+}
+
+/**
+ * Path of a temporary file next to the cache
+ */
+fn temporary_path(cache_path: &Path, name: &str) -> PathBuf {
+    let mut path = cache_path.as_os_str().to_owned();
+    path.push(format!(".{}.tmp", name));
+    PathBuf::from(path)
+}
+
+/**
+ * Temporary file used to store a single column while converting, the file is removed when dropped
+ */
+struct ColumnWriter {
+    path: PathBuf,
+    writer: BufWriter<File>,
+}
+
+impl ColumnWriter {
+    fn create(cache_path: &Path, column: &str) -> io::Result<ColumnWriter> {
+        let path = temporary_path(cache_path, column);
+        let writer = BufWriter::new(File::create(&path)?);
+        Ok(ColumnWriter {
+            path,
+            writer,
+        })
+    }
+
+    /**
+     * Append the column to the cache file
+     */
+    fn append_to(mut self, output: &mut impl Write) -> io::Result<()> {
+        self.writer.flush()?;
+        let mut reader = BufReader::new(File::open(&self.path)?);
+        io::copy(&mut reader, output)?;
+        Ok(())
+    }
+}
+
+impl Drop for ColumnWriter {
+    fn drop(&mut self) {
+        //Conversion that failed leaves no temporary files behind
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/**
+ * Convert the canvas history to the binary cache, fails if the files overlap
+ */
+pub(crate) fn convert(files: &[PathBuf], year: &PlaceDataSet, cache_path: &Path) -> io::Result<()> {
+    info!("Converting tile data to cache {}", cache_path.display());
+    let sources = files.iter().map(|path| source_file(path)).collect::<io::Result<Vec<_>>>()?;
+    let mut time_column = ColumnWriter::create(cache_path, "time")?;
+    let mut user_column = ColumnWriter::create(cache_path, "user")?;
+    let mut colour_column = ColumnWriter::create(cache_path, "colour")?;
+    let mut x_column = ColumnWriter::create(cache_path, "x")?;
+    let mut y_column = ColumnWriter::create(cache_path, "y")?;
+
+    let users = UserTable::new();
+    let mut palette: HashMap<[u8; 3], u8> = HashMap::new();
+    let mut palette_table: Vec<[u8; 3]> = Vec::new();
+    let mut extras: Vec<ExtraCoordinate> = Vec::new();
+    let mut base_time: Option<i64> = None;
+    let mut row_count: u64 = 0;
+    //Last edit read so far and the file it is from, files must not overlap
+    let mut file_end: Option<(&PathBuf, PrimitiveDateTime)> = None;
+
+    for (path, line_result) in files.iter().flat_map(|path| tile_data_lines(std::slice::from_ref(path)).map(move |l| (path, l))) {
+        let line = match line_result {
+            Ok(l) => { l }
+            Err(e) => {
+                warn!("Failed to obtain line from tile data: {}", e);
+                continue;
+            }
+        };
+        let row = match parse_row(&line, year, &users) {
+            Some(v) => { v }
+            None => { continue; }
+        };
+        if let Some((previous, end)) = file_end.filter(|(p, _)| *p != path) {
+            check_file_boundary(previous, end, path, row.timestamp)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        }
+        file_end = Some((path, row.timestamp));
+        let time = to_millis(row.timestamp);
+
+        let base = *base_time.get_or_insert(time);
+        let offset = i32::try_from(time - base)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Tile data spans too long time for the cache"))?;
+        let colour = match palette.get(&row.pixel_color) {
+            Some(index) => { *index }
+            None => {
+                let index = u8::try_from(palette_table.len())
+                    .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Tile data has too many colours for the cache"))?;
+                palette.insert(row.pixel_color, index);
+                palette_table.push(row.pixel_color);
+                index
+            }
+        };
+        let (x, y) = match row.coordinate {
+            LineCoordinate::Tile(t) => { (t.x, t.y) }
+            LineCoordinate::Region(r) => {
+                extras.push(ExtraCoordinate {
+                    row: row_count,
+                    kind: KIND_REGION,
+                    values: [r.left, r.top, r.right, r.bottom],
+                });
+                (0, 0)
+            }
+            LineCoordinate::Circle(c) => {
+                extras.push(ExtraCoordinate {
+                    row: row_count,
+                    kind: KIND_CIRCLE,
+                    values: [c.x, c.y, c.r, 0],
+                });
+                (0, 0)
+            }
+        };
+
+        time_column.writer.write_all(&offset.to_le_bytes())?;
+        user_column.writer.write_all(&row.user_id.0.to_le_bytes())?;
+        colour_column.writer.write_all(&[colour])?;
+        x_column.writer.write_all(&x.to_le_bytes())?;
+        y_column.writer.write_all(&y.to_le_bytes())?;
+        row_count += 1;
+    }
+
+    //Write to a temporary file so an interrupted conversion is not mistaken for a cache
+    let output_path = temporary_path(cache_path, "cache");
+    let mut output = BufWriter::new(File::create(&output_path)?);
+    output.write_all(CACHE_MAGIC)?;
+    output.write_all(&[dataset_code(year)])?;
+    output.write_all(&(sources.len() as u16).to_le_bytes())?;
+    for (path, size) in &sources {
+        output.write_all(&(path.len() as u16).to_le_bytes())?;
+        output.write_all(path.as_bytes())?;
+        output.write_all(&size.to_le_bytes())?;
+    }
+    output.write_all(&row_count.to_le_bytes())?;
+    output.write_all(&base_time.unwrap_or(0).to_le_bytes())?;
+    output.write_all(&(palette_table.len() as u16).to_le_bytes())?;
+    for colour in &palette_table {
+        let colour = format_colour(colour);
+        output.write_all(&[colour.len() as u8])?;
+        output.write_all(colour.as_bytes())?;
+    }
+    let user_table = users.names();
+    output.write_all(&(user_table.len() as u32).to_le_bytes())?;
+    for user in &user_table {
+        output.write_all(&(user.len() as u16).to_le_bytes())?;
+        output.write_all(user.as_bytes())?;
+    }
+    output.write_all(&(extras.len() as u32).to_le_bytes())?;
+    for extra in &extras {
+        output.write_all(&extra.row.to_le_bytes())?;
+        output.write_all(&[extra.kind])?;
+        for value in extra.values {
+            output.write_all(&value.to_le_bytes())?;
+        }
+    }
+    time_column.append_to(&mut output)?;
+    user_column.append_to(&mut output)?;
+    colour_column.append_to(&mut output)?;
+    x_column.append_to(&mut output)?;
+    y_column.append_to(&mut output)?;
+    output.flush()?;
+    drop(output);
+    fs::rename(&output_path, cache_path)?;
+
+    info!("Converted {} rows with {} users", row_count, user_table.len());
+    Ok(())
+}
+
+fn read_u8(reader: &mut impl Read) -> io::Result<u8> {
+    let mut buf = [0u8; 1];
+    reader.read_exact(&mut buf)?;
+    Ok(buf[0])
+}
+
+fn read_u16(reader: &mut impl Read) -> io::Result<u16> {
+    let mut buf = [0u8; 2];
+    reader.read_exact(&mut buf)?;
+    Ok(u16::from_le_bytes(buf))
+}
+
+fn read_i16(reader: &mut impl Read) -> io::Result<i16> {
+    let mut buf = [0u8; 2];
+    reader.read_exact(&mut buf)?;
+    Ok(i16::from_le_bytes(buf))
+}
+
+fn read_u32(reader: &mut impl Read) -> io::Result<u32> {
+    let mut buf = [0u8; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_i32(reader: &mut impl Read) -> io::Result<i32> {
+    let mut buf = [0u8; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(i32::from_le_bytes(buf))
+}
+
+fn read_u64(reader: &mut impl Read) -> io::Result<u64> {
+    let mut buf = [0u8; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn read_string(reader: &mut impl Read, len: usize) -> io::Result<String> {
+    let mut buf = vec![0u8; len];
+    reader.read_exact(&mut buf)?;
+    String::from_utf8(buf)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/**
+ * Binary cache of the canvas history
+ */
+pub(crate) struct Cache {
+    path: PathBuf,
+    year: PlaceDataSet,
+    sources: Vec<(String, u64)>,
+    row_count: u64,
+    base_time: i64,
+    palette: Vec<[u8; 3]>,
+    users: Vec<String>,
+    user_count: u32,
+    extras: Vec<ExtraCoordinate>,
+    columns_start: u64,
+}
+
+impl Cache {
+    /**
+     * Open the cache and read the tables stored before the columns
+     */
+    pub(crate) fn open(path: &Path) -> io::Result<Cache> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != CACHE_MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "File is not a tile data cache"));
+        }
+        let year = dataset_from_code(read_u8(&mut reader)?)?;
+        let source_count = read_u16(&mut reader)?;
+        let mut sources = Vec::with_capacity(source_count as usize);
+        for _ in 0..source_count {
+            let len = read_u16(&mut reader)? as usize;
+            let path = read_string(&mut reader, len)?;
+            sources.push((path, read_u64(&mut reader)?));
+        }
+        let row_count = read_u64(&mut reader)?;
+        let base_time = read_u64(&mut reader)? as i64;
+
+        let palette_len = read_u16(&mut reader)?;
+        let mut palette = Vec::with_capacity(palette_len as usize);
+        for _ in 0..palette_len {
+            let len = read_u8(&mut reader)? as usize;
+            let colour = parse_colour(&read_string(&mut reader, len)?)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Invalid colour in cache palette"))?;
+            palette.push(colour);
+        }
+        let user_count = read_u32(&mut reader)?;
+        let mut users = Vec::with_capacity(user_count as usize);
+        for _ in 0..user_count {
+            let len = read_u16(&mut reader)? as usize;
+            users.push(read_string(&mut reader, len)?);
+        }
+        let extra_count = read_u32(&mut reader)?;
+        let mut extras = Vec::with_capacity(extra_count as usize);
+        for _ in 0..extra_count {
+            let row = read_u64(&mut reader)?;
+            let kind = read_u8(&mut reader)?;
+            let mut values = [0i16; 4];
+            for value in values.iter_mut() {
+                *value = read_i16(&mut reader)?;
+            }
+            extras.push(ExtraCoordinate {
+                row,
+                kind,
+                values,
+            });
+        }
+        let columns_start = reader.stream_position()?;
+
+        Ok(Cache {
+            path: path.to_path_buf(),
+            year,
+            sources,
+            row_count,
+            base_time,
+            palette,
+            users,
+            user_count,
+            extras,
+            columns_start,
+        })
+    }
+
+    /**
+     * Take the hashed user ids ordered by user id, the rows refer to users by the index
+     */
+    pub(crate) fn take_users(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.users)
+    }
+
+    /**
+     * Data set the cache was created from
+     */
+    pub(crate) fn year(&self) -> &PlaceDataSet {
+        &self.year
+    }
+
+    /**
+     * Check that the cache was created from the given files and they have not changed since
+     */
+    pub(crate) fn check_sources(&self, files: &[PathBuf]) -> Result<(), String> {
+        if files.len() != self.sources.len() {
+            return Err(format!("cache was created from {} files but {} files are given", self.sources.len(), files.len()));
+        }
+        for (path, (source_path, source_size)) in files.iter().zip(&self.sources) {
+            let (path, size) = source_file(path)
+                .map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+            if &path != source_path {
+                return Err(format!("cache was created from {} but {} is given", source_path, path));
+            }
+            if size != *source_size {
+                return Err(format!("size of {} has changed since the cache was created", path));
+            }
+        }
+        Ok(())
+    }
+
+    /**
+     * Number of rows in the cache
+     */
+    pub(crate) fn row_count(&self) -> u64 {
+        self.row_count
+    }
+
+    /**
+     * Time of the row, read from the time column without reading the other rows
+     */
+    pub(crate) fn row_time(&self, row: u64) -> io::Result<PrimitiveDateTime> {
+        if row >= self.row_count {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Row is not in the cache"));
+        }
+        let offset = read_i32(&mut self.column_reader(row * 4)?)?;
+        from_millis(self.base_time + offset as i64)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Cache contains invalid time"))
+    }
+
+    /**
+     * Open a reader positioned at the start of a column
+     */
+    fn column_reader(&self, offset: u64) -> io::Result<BufReader<File>> {
+        let mut file = File::open(&self.path)?;
+        file.seek(SeekFrom::Start(self.columns_start + offset))?;
+        Ok(BufReader::new(file))
+    }
+
+    /**
+     * Iterate over the rows of the cache in order
+     */
+    pub(crate) fn rows(&self) -> io::Result<CacheRows<'_>> {
+        let rows = self.row_count;
+        Ok(CacheRows {
+            cache: self,
+            row: 0,
+            next_extra: 0,
+            time: self.column_reader(0)?,
+            user: self.column_reader(rows * 4)?,
+            colour: self.column_reader(rows * 8)?,
+            x: self.column_reader(rows * 9)?,
+            y: self.column_reader(rows * 11)?,
+        })
+    }
+}
+
+/**
+ * Iterator over the rows stored in the cache
+ */
+pub(crate) struct CacheRows<'a> {
+    cache: &'a Cache,
+    row: u64,
+    next_extra: usize,
+    time: BufReader<File>,
+    user: BufReader<File>,
+    colour: BufReader<File>,
+    x: BufReader<File>,
+    y: BufReader<File>,
+}
+
+impl CacheRows<'_> {
+    fn read_row(&mut self) -> io::Result<CanvasLine> {
+        let time = read_i32(&mut self.time)?;
+        let user = read_u32(&mut self.user)?;
+        let colour = read_u8(&mut self.colour)? as usize;
+        let x = read_i16(&mut self.x)?;
+        let y = read_i16(&mut self.y)?;
+
+        let coordinate = match self.cache.extras.get(self.next_extra) {
+            Some(extra) if extra.row == self.row => {
+                self.next_extra += 1;
+                let [a, b, c, d] = extra.values;
+                if extra.kind == KIND_CIRCLE {
+                    LineCoordinate::Circle(TileCircle {
+                        x: a,
+                        y: b,
+                        r: c,
+                    })
+                } else {
+                    LineCoordinate::Region(TileRegion {
+                        left: a,
+                        top: b,
+                        right: c,
+                        bottom: d,
+                    })
+                }
+            }
+            _ => {
+                LineCoordinate::Tile(TileLocation {
+                    x,
+                    y,
+                })
+            }
+        };
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Cache refers to a missing table entry");
+        if user >= self.cache.user_count {
+            return Err(invalid());
+        }
+        Ok(CanvasLine {
+            timestamp: from_millis(self.cache.base_time + time as i64).ok_or_else(invalid)?,
+            user_id: UserId(user),
+            pixel_color: *self.cache.palette.get(colour).ok_or_else(invalid)?,
+            coordinate,
+        })
+    }
+}
+
+impl Iterator for CacheRows<'_> {
+    type Item = io::Result<CanvasLine>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.row >= self.cache.row_count {
+            return None;
+        }
+        let row = self.read_row();
+        self.row += 1;
+        if row.is_err() {
+            //Columns can not be trusted to be aligned after an error
+            self.row = self.cache.row_count;
+        }
+        Some(row)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::input::{TempFile, write_csv};
+    use super::*;
+
+    #[test]
+    fn cache_rows_match_csv() {
+        let content = "\
+timestamp,user,coordinate,pixel_color
+2023-07-20 13:00:00.000 UTC,A==,\"-5,7\",#FF4500
+2023-07-20 13:00:00.500 UTC,B==,\"-1500,-1000,-1490,-995\",#000000
+2023-07-20 13:00:01 UTC,A==,\"{X: 12, Y: -3, R: 4}\",#FFFFFF
+2023-07-20 13:00:02.125 UTC,C==,\"1499,999\",#000000
+2023-07-20 14:30:00.000 UTC,B==,\"0,0\",#FF4500
+";
+        let csv = write_csv("cache", content);
+        let cache_file = TempFile::new("cache", "cache");
+        let files = [csv.path.clone()];
+        convert(&files, &PlaceDataSet::Place2023, &cache_file.path).expect("Failed to convert test data");
+
+        let mut cache = Cache::open(&cache_file.path).expect("Failed to open cache");
+        assert_eq!(cache.year(), &PlaceDataSet::Place2023);
+        assert_eq!(cache.check_sources(&files), Ok(()));
+        let users = UserTable::from_names(cache.take_users());
+        let expected: Vec<CanvasLine> = content.lines().skip(1)
+            .map(|line| parse_row(line, &PlaceDataSet::Place2023, &users).expect("Valid test row"))
+            .collect();
+        let rows: Vec<CanvasLine> = cache.rows().expect("Failed to read cache")
+            .map(|row| row.expect("Valid cache row"))
+            .collect();
+        assert_eq!(rows, expected);
+
+        //Changed source file is not accepted
+        fs::write(&csv.path, format!("{}2023-07-20 14:30:01.000 UTC,B==,\"0,0\",#FF4500\n", content))
+            .expect("Failed to write test data");
+        assert!(cache.check_sources(&files).is_err());
+    }
+}
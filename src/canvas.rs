@@ -0,0 +1,392 @@
+use std::sync::Mutex;
+use time::PrimitiveDateTime;
+use crate::{CanvasLine, from_millis, LineCoordinate, TileLocation, TileRegion, to_millis};
+use crate::users::UserId;
+
+/**
+ * Writer of tiles that have not been edited
+ */
+const NO_WRITER: u32 = u32::MAX;
+
+/**
+ * Last edit of a tile
+ */
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(crate) struct CanvasPixel {
+    pub(crate) colour: [u8; 3],
+    pub(crate) writer: UserId,
+    pub(crate) time: PrimitiveDateTime,
+}
+
+/**
+ * Last colour, writer and edit time of every tile of the canvas stored in dense arrays.
+ * Edits are applied with their position in the data and an edit only replaces earlier edits,
+ * so states built from different parts of the data can be merged
+ */
+#[derive(Clone)]
+pub(crate) struct CanvasState {
+    bounds: TileRegion,
+    width: usize,
+    //Times are stored as milliseconds from the first applied edit to keep the state small
+    base_time: Option<i64>,
+    colours: Vec<[u8; 3]>,
+    writers: Vec<u32>,
+    times: Vec<i32>,
+    positions: Vec<u64>,
+}
+
+impl CanvasState {
+    /**
+     * Create state of the canvas covering the given tiles with no edits
+     */
+    pub(crate) fn new(bounds: TileRegion) -> CanvasState {
+        let width = (bounds.right as i32 - bounds.left as i32 + 1).max(0) as usize;
+        let height = (bounds.bottom as i32 - bounds.top as i32 + 1).max(0) as usize;
+        let size = width * height;
+        CanvasState {
+            bounds,
+            width,
+            base_time: None,
+            colours: vec![[0, 0, 0]; size],
+            writers: vec![NO_WRITER; size],
+            times: vec![0; size],
+            positions: vec![0; size],
+        }
+    }
+
+    /**
+     * Tiles covered by the state
+     */
+    pub(crate) fn bounds(&self) -> &TileRegion {
+        &self.bounds
+    }
+
+    fn index(&self, x: i16, y: i16) -> Option<usize> {
+        if !self.bounds.contains_point(x, y) {
+            return None;
+        }
+        Some((y as i32 - self.bounds.top as i32) as usize * self.width + (x as i32 - self.bounds.left as i32) as usize)
+    }
+
+    /**
+     * Apply the edit at the given position in the data, tiles outside of the canvas are ignored
+     */
+    pub(crate) fn apply(&mut self, position: u64, row: &CanvasLine) {
+        let time = to_millis(row.timestamp);
+        row.coordinate.for_each_point(|tile| {
+            if let Some(i) = self.index(tile.x, tile.y) {
+                self.set(i, position, row.pixel_color, row.user_id.0, time);
+            }
+        });
+    }
+
+    fn set(&mut self, i: usize, position: u64, colour: [u8; 3], writer: u32, time: i64) {
+        //Edit at the same position is the same edit
+        if self.writers[i] != NO_WRITER && self.positions[i] >= position {
+            return;
+        }
+        let base_time = *self.base_time.get_or_insert(time);
+        self.colours[i] = colour;
+        self.writers[i] = writer;
+        self.times[i] = i32::try_from(time - base_time).expect("Edits of the canvas span too long time");
+        self.positions[i] = position;
+    }
+
+    fn time_at(&self, i: usize) -> i64 {
+        self.base_time.unwrap_or(0) + self.times[i] as i64
+    }
+
+    /**
+     * Merge state built from a different set of rows covering the same tiles, later edits are kept
+     */
+    pub(crate) fn merge(mut self, other: CanvasState) -> CanvasState {
+        assert_eq!(self.bounds, other.bounds, "Merged canvas states cover different tiles");
+        for i in 0..other.writers.len() {
+            if other.writers[i] != NO_WRITER {
+                self.set(i, other.positions[i], other.colours[i], other.writers[i], other.time_at(i));
+            }
+        }
+        self
+    }
+
+    /**
+     * Join states covering consecutive bands of tile rows of the bounds, from top to bottom
+     */
+    fn join_rows(bounds: TileRegion, bands: Vec<CanvasState>) -> CanvasState {
+        let mut state = CanvasState::new(bounds);
+        state.base_time = bands.iter().find_map(|band| band.base_time);
+        let mut start = 0;
+        for band in bands {
+            assert_eq!(band.width, state.width, "Joined canvas states have different widths");
+            let end = start + band.writers.len();
+            state.colours[start..end].copy_from_slice(&band.colours);
+            state.writers[start..end].copy_from_slice(&band.writers);
+            state.positions[start..end].copy_from_slice(&band.positions);
+            //Times are moved to the base time of the joined state, bands without edits have no times
+            if let (Some(band_base), Some(base)) = (band.base_time, state.base_time) {
+                for (time, band_time) in state.times[start..end].iter_mut().zip(band.times) {
+                    *time = i32::try_from(band_time as i64 + band_base - base).expect("Edits of the canvas span too long time");
+                }
+            }
+            start = end;
+        }
+        assert_eq!(start, state.writers.len(), "Joined canvas states do not cover the bounds");
+        state
+    }
+
+    fn pixel_at(&self, i: usize) -> Option<CanvasPixel> {
+        if self.writers[i] == NO_WRITER {
+            return None;
+        }
+        Some(CanvasPixel {
+            colour: self.colours[i],
+            writer: UserId(self.writers[i]),
+            time: from_millis(self.time_at(i)).expect("Canvas contains invalid time"),
+        })
+    }
+
+    /**
+     * Last edit of the tile, None if the tile has not been edited or is outside of the state
+     */
+    pub(crate) fn pixel(&self, x: i16, y: i16) -> Option<CanvasPixel> {
+        self.pixel_at(self.index(x, y)?)
+    }
+
+    fn location(&self, i: usize) -> TileLocation {
+        TileLocation {
+            x: (self.bounds.left as i32 + (i % self.width) as i32) as i16,
+            y: (self.bounds.top as i32 + (i / self.width) as i32) as i16,
+        }
+    }
+
+    /**
+     * Iterate over the edited tiles with their last edit
+     */
+    pub(crate) fn pixels(&self) -> impl Iterator<Item=(TileLocation, CanvasPixel)> + '_ {
+        (0..self.writers.len()).filter_map(|i| {
+            Some((self.location(i), self.pixel_at(i)?))
+        })
+    }
+
+    /**
+     * Iterate over the tiles where the last edit was made by the user
+     */
+    pub(crate) fn pixels_of(&self, writer: UserId) -> impl Iterator<Item=(TileLocation, CanvasPixel)> + '_ {
+        (0..self.writers.len()).filter(move |i| self.writers[*i] == writer.0).filter_map(|i| {
+            Some((self.location(i), self.pixel_at(i)?))
+        })
+    }
+}
+
+/**
+ * Number of bands of tile rows a shared canvas state is split to
+ */
+const SHARED_BANDS: usize = 64;
+
+/**
+ * State of the canvas that threads apply edits to at the same time. The canvas is split to bands
+ * of tile rows which are locked separately, so there is a single copy of the canvas however many
+ * threads read the rows and threads rarely wait for each other
+ */
+pub(crate) struct SharedCanvasState {
+    bounds: TileRegion,
+    band_height: usize,
+    bands: Vec<Mutex<CanvasState>>,
+}
+
+impl SharedCanvasState {
+    pub(crate) fn new(bounds: TileRegion) -> SharedCanvasState {
+        let height = (bounds.bottom as i32 - bounds.top as i32 + 1).max(0) as usize;
+        let band_height = height.div_ceil(SHARED_BANDS).max(1);
+        let mut state = SharedCanvasState {
+            bounds,
+            band_height,
+            bands: Vec::new(),
+        };
+        state.bands = (0..height.div_ceil(band_height)).map(|band| {
+            Mutex::new(CanvasState::new(state.band_bounds(band)))
+        }).collect();
+        state
+    }
+
+    fn band_bounds(&self, band: usize) -> TileRegion {
+        let top = self.bounds.top as i32 + (band * self.band_height) as i32;
+        TileRegion {
+            left: self.bounds.left,
+            top: top as i16,
+            right: self.bounds.right,
+            bottom: (top + self.band_height as i32 - 1).min(self.bounds.bottom as i32) as i16,
+        }
+    }
+
+    fn apply_to_band(&self, band: usize, position: u64, row: &CanvasLine) {
+        self.bands[band].lock().expect("Canvas state lock poisoned").apply(position, row);
+    }
+
+    /**
+     * Apply the edit at the given position in the data, only the bands the edit changes are locked
+     */
+    pub(crate) fn apply(&self, position: u64, row: &CanvasLine) {
+        match &row.coordinate {
+            LineCoordinate::Tile(t) => {
+                if self.bounds.contains_point(t.x, t.y) {
+                    self.apply_to_band((t.y as i32 - self.bounds.top as i32) as usize / self.band_height, position, row);
+                }
+            }
+            coordinate => {
+                for band in 0..self.bands.len() {
+                    if coordinate.intersects(&self.band_bounds(band)) {
+                        self.apply_to_band(band, position, row);
+                    }
+                }
+            }
+        }
+    }
+
+    /**
+     * State of the whole canvas once all edits have been applied
+     */
+    pub(crate) fn into_state(self) -> CanvasState {
+        let bands = self.bands.into_iter()
+            .map(|band| band.into_inner().expect("Canvas state lock poisoned"))
+            .collect();
+        CanvasState::join_rows(self.bounds, bands)
+    }
+}
+
+/**
+ * Format colour in the hex format of the canvas history
+ */
+pub(crate) fn format_colour(colour: &[u8; 3]) -> String {
+    format!("#{:02X}{:02X}{:02X}", colour[0], colour[1], colour[2])
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::PlaceDataSet;
+    use crate::users::UserTable;
+    use super::*;
+
+    fn bounds() -> TileRegion {
+        TileRegion {
+            left: -2,
+            top: -2,
+            right: 7,
+            bottom: 7,
+        }
+    }
+
+    fn rows(users: &UserTable, lines: &[&str]) -> Vec<CanvasLine> {
+        lines.iter().map(|line| {
+            let (_, row) = PlaceDataSet::Place2023.parse_line(line, users).expect("Valid test row");
+            row
+        }).collect()
+    }
+
+    #[test]
+    fn apply_tile_region_and_circle() {
+        let users = UserTable::new();
+        let rows = rows(&users, &[
+            "2023-07-20 13:00:01.000 UTC,A==,\"1,1\",#FF0000",
+            "2023-07-20 13:00:00.000 UTC,B==,\"-4,-4,0,-1\",#00FF00",
+            "2023-07-20 13:00:02.500 UTC,C==,\"{X: 7, Y: 7, R: 2}\",#0000FF",
+        ]);
+        let mut state = CanvasState::new(bounds());
+        for (position, row) in rows.iter().enumerate() {
+            state.apply(position as u64, row);
+        }
+
+        let a = CanvasPixel {
+            colour: [255, 0, 0],
+            writer: users.intern("A=="),
+            time: time::macros::datetime!(2023-07-20 13:00:01),
+        };
+        assert_eq!(state.pixel(1, 1), Some(a));
+        //Region is cut to the bounds of the state, edit before the first applied one keeps its time
+        for x in -2..=0 {
+            for y in -2..=-1 {
+                let pixel = state.pixel(x, y).expect("Tile in region");
+                assert_eq!(pixel.writer, users.intern("B=="));
+                assert_eq!(pixel.time, time::macros::datetime!(2023-07-20 13:00:00));
+            }
+        }
+        assert_eq!(state.pixel(0, 0), None);
+        assert_eq!(state.pixel(-3, -3), None);
+        //Circle of radius two covers the centre and its neighbours, cut to the bounds
+        let circle: Vec<TileLocation> = state.pixels_of(users.intern("C==")).map(|(tile, _)| tile).collect();
+        assert_eq!(circle, vec![
+            TileLocation { x: 6, y: 6 },
+            TileLocation { x: 7, y: 6 },
+            TileLocation { x: 6, y: 7 },
+            TileLocation { x: 7, y: 7 },
+        ]);
+        assert_eq!(state.pixels().count(), 1 + 6 + 4);
+    }
+
+    #[test]
+    fn merge_keeps_later_edits() {
+        let users = UserTable::new();
+        let rows = rows(&users, &[
+            "2023-07-20 13:00:00.000 UTC,A==,\"1,1\",#FF0000",
+            "2023-07-20 13:00:00.000 UTC,B==,\"0,0,2,2\",#00FF00",
+            "2023-07-20 13:05:00.000 UTC,C==,\"{X: 1, Y: 1, R: 2}\",#0000FF",
+            "2023-07-20 12:59:00.000 UTC,A==,\"2,2\",#FF0000",
+            "2023-07-20 13:06:00.000 UTC,B==,\"1,0\",#000000",
+            "2023-07-20 13:06:00.000 UTC,A==,\"-2,-2,7,-2\",#FFFFFF",
+        ]);
+        let mut sequential = CanvasState::new(bounds());
+        for (position, row) in rows.iter().enumerate() {
+            sequential.apply(position as u64, row);
+        }
+
+        //Rows split between states and applied in reverse order
+        let mut even = CanvasState::new(bounds());
+        let mut odd = CanvasState::new(bounds());
+        for (position, row) in rows.iter().enumerate().rev() {
+            let state = if position % 2 == 0 { &mut even } else { &mut odd };
+            state.apply(position as u64, row);
+        }
+        let expected: Vec<(TileLocation, CanvasPixel)> = sequential.pixels().collect();
+        let merged: Vec<(TileLocation, CanvasPixel)> = even.clone().merge(odd.clone()).pixels().collect();
+        assert_eq!(merged, expected);
+        let merged: Vec<(TileLocation, CanvasPixel)> = odd.merge(even).pixels().collect();
+        assert_eq!(merged, expected);
+
+        //Merging a state with itself does not change it
+        let merged: Vec<(TileLocation, CanvasPixel)> = sequential.clone().merge(sequential).pixels().collect();
+        assert_eq!(merged, expected);
+        assert_eq!(expected.iter().find(|(t, _)| *t == TileLocation { x: 1, y: 0 }).map(|(_, p)| p.colour), Some([0, 0, 0]));
+        assert_eq!(expected.iter().find(|(t, _)| *t == TileLocation { x: 2, y: 2 }).map(|(_, p)| p.colour), Some([255, 0, 0]));
+    }
+
+    #[test]
+    fn shared_state_matches_sequential() {
+        let users = UserTable::new();
+        let rows = rows(&users, &[
+            "2023-07-20 13:00:00.000 UTC,A==,\"1,1\",#FF0000",
+            "2023-07-20 13:10:00.000 UTC,B==,\"-2,-2,7,0\",#00FF00",
+            "2023-07-20 12:00:00.000 UTC,C==,\"{X: 2, Y: 6, R: 3}\",#0000FF",
+            "2023-07-20 13:00:00.000 UTC,A==,\"3,-2\",#FF0000",
+            "2023-07-20 14:00:00.000 UTC,B==,\"7,7\",#000000",
+            "2023-07-20 11:00:00.000 UTC,A==,\"-1,4,1,5\",#FFFFFF",
+            "2023-07-20 14:00:00.000 UTC,C==,\"9,9\",#FFFFFF",
+        ]);
+        let mut sequential = CanvasState::new(bounds());
+        for (position, row) in rows.iter().enumerate() {
+            sequential.apply(position as u64, row);
+        }
+        //Bounds of ten rows are split to bands of one row, rows are applied in reverse order
+        //and some bands are not edited
+        let shared = SharedCanvasState::new(bounds());
+        assert_eq!(shared.bands.len(), 10);
+        for (position, row) in rows.iter().enumerate().rev() {
+            shared.apply(position as u64, row);
+        }
+        let joined = shared.into_state();
+        assert_eq!(joined.bounds(), sequential.bounds());
+        let expected: Vec<(TileLocation, CanvasPixel)> = sequential.pixels().collect();
+        assert_eq!(joined.pixels().collect::<Vec<_>>(), expected);
+        assert!(expected.iter().any(|(tile, _)| tile.y == 7));
+        assert!(expected.iter().all(|(tile, _)| tile.y != 2));
+    }
+}
@@ -0,0 +1,662 @@
+use std::fs::{self, File};
+use std::io;
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use flate2::read::MultiGzDecoder;
+use log::warn;
+use memmap2::Mmap;
+use rayon::iter::{IndexedParallelIterator, IntoParallelIterator, ParallelBridge, ParallelIterator};
+use serde::Deserialize;
+use time::PrimitiveDateTime;
+use crate::{CanvasLine, PlaceDataSet};
+use crate::cache::{Cache, CacheRows};
+use crate::users::UserTable;
+
+/**
+ * Location of the canvas history, either a single file or a list of files,
+ * each entry can be a path to a file, a glob pattern or a directory
+ */
+#[derive(Deserialize, Clone)]
+#[serde(untagged)]
+pub(crate) enum DataLocation {
+    Single(String),
+    Multiple(Vec<String>),
+}
+
+impl DataLocation {
+    /**
+     * Resolve the location to the list of files in the order they should be read
+     */
+    pub(crate) fn files(&self) -> Vec<PathBuf> {
+        let entries = match self {
+            DataLocation::Single(s) => { std::slice::from_ref(s) }
+            DataLocation::Multiple(v) => { v.as_slice() }
+        };
+        let mut files = Vec::new();
+        for entry in entries {
+            files.extend(resolve_entry(entry));
+        }
+        if files.is_empty() {
+            panic!("No tile data files found in csv_location");
+        }
+        files
+    }
+}
+
+/**
+ * Resolve single entry of the csv_location to files, directories and glob patterns
+ * are expanded to files sorted by name
+ */
+fn resolve_entry(entry: &str) -> Vec<PathBuf> {
+    let path = Path::new(entry);
+    if path.is_dir() {
+        let mut files: Vec<PathBuf> = fs::read_dir(path)
+            .unwrap_or_else(|e| panic!("Failed to read directory {}: {}", entry, e))
+            .filter_map(|e| e.ok().map(|e| e.path()))
+            .filter(|p| is_tile_data_file(p))
+            .collect();
+        files.sort();
+        return files;
+    }
+    if entry.contains(['*', '?', '[']) {
+        let mut files: Vec<PathBuf> = glob::glob(entry)
+            .unwrap_or_else(|e| panic!("Invalid glob pattern {}: {}", entry, e))
+            .filter_map(|p| p.ok())
+            .filter(|p| p.is_file())
+            .collect();
+        files.sort();
+        return files;
+    }
+    vec![path.to_path_buf()]
+}
+
+/**
+ * Check if the file in a directory looks like canvas history
+ */
+fn is_tile_data_file(path: &Path) -> bool {
+    if !path.is_file() {
+        return false;
+    }
+    let name = match path.file_name().and_then(|n| n.to_str()) {
+        Some(n) => { n.to_ascii_lowercase() }
+        None => { return false; }
+    };
+    name.ends_with(".csv") || name.ends_with(".csv.gz") || name.ends_with(".csv.gzip")
+}
+
+/**
+ * Check if the file is gzip compressed based on the file extension
+ */
+fn is_compressed(path: &Path) -> bool {
+    path.extension()
+        .map(|e| e.eq_ignore_ascii_case("gz") || e.eq_ignore_ascii_case("gzip"))
+        .unwrap_or(false)
+}
+
+/**
+ * Open the tile data for reading, gzip compressed files are decompressed while reading
+ */
+pub(crate) fn open_tile_data(path: &Path) -> BufReader<Box<dyn Read + Send>> {
+    let file = File::open(path)
+        .unwrap_or_else(|e| panic!("Failed to open tile data {}: {}", path.display(), e));
+    let source: Box<dyn Read + Send> = if is_compressed(path) {
+        //Reddit publishes the data as concatenated gzip members
+        Box::new(MultiGzDecoder::new(BufReader::new(file)))
+    } else {
+        Box::new(file)
+    };
+    BufReader::new(source)
+}
+
+/**
+ * Iterate over the data lines of all files in order, skipping the header of each file
+ */
+pub(crate) fn tile_data_lines(files: &[PathBuf]) -> impl Iterator<Item=io::Result<String>> + Send + '_ {
+    files.iter().flat_map(|path| {
+        let mut lines = open_tile_data(path).lines();
+        if lines.next().is_none() {
+            panic!("Could not skip CSV header in {}", path.display());
+        }
+        lines
+    })
+}
+
+/**
+ * Detect the data set of the file from the CSV header, or from the first data line
+ * if the header is not recognized
+ */
+fn detect_file_year(path: &Path) -> Option<PlaceDataSet> {
+    let header = open_tile_data(path).lines().next()?
+        .unwrap_or_else(|e| panic!("Failed to read {}: {}", path.display(), e));
+    let columns: Vec<&str> = header.trim().split(',').collect();
+    match columns.as_slice() {
+        ["timestamp", "user_id", "pixel_color", "coordinate"] => { return Some(PlaceDataSet::Place2022); }
+        ["timestamp", "user", "coordinate", "pixel_color"] => { return Some(PlaceDataSet::Place2023); }
+        ["ts", _, "x_coordinate", "y_coordinate", "color"] => { return Some(PlaceDataSet::Place2017); }
+        _ => {}
+    }
+
+    let line = tile_data_lines(std::slice::from_ref(&path.to_path_buf()))
+        .next()?
+        .ok()?;
+    let users = UserTable::new();
+    [PlaceDataSet::Place2023, PlaceDataSet::Place2022, PlaceDataSet::Place2017].into_iter()
+        .find(|year| year.parse_line(&line, &users).is_ok())
+}
+
+/**
+ * Detect the data set of the files, configured year is used when the files are not recognized
+ * and it is an error if it does not match the detected data set
+ */
+pub(crate) fn detect_year(files: &[PathBuf], configured: Option<&PlaceDataSet>) -> PlaceDataSet {
+    let mut detected: Option<PlaceDataSet> = None;
+    for path in files {
+        let year = match detect_file_year(path) {
+            Some(y) => { y }
+            None => {
+                warn!("Could not detect data set of {}", path.display());
+                continue;
+            }
+        };
+        if let Some(configured) = configured {
+            if configured != &year {
+                panic!("Configured year is {:?} but {} is from {:?}, remove year from the configuration to detect it automatically",
+                       configured, path.display(), year);
+            }
+        }
+        if let Some(previous) = &detected {
+            if previous != &year {
+                panic!("Tile data files are from different data sets, {} is from {:?} and earlier files from {:?}",
+                       path.display(), year, previous);
+            }
+        }
+        detected = Some(year);
+    }
+    match (detected, configured) {
+        (Some(year), _) => { year }
+        (None, Some(year)) => { year.clone() }
+        (None, None) => { panic!("Could not detect data set of the tile data, set year in the configuration"); }
+    }
+}
+
+/**
+ * Get the timestamp of the first data line in the file
+ */
+fn first_timestamp(path: &Path, year: &PlaceDataSet) -> Option<PrimitiveDateTime> {
+    let line = tile_data_lines(std::slice::from_ref(&path.to_path_buf()))
+        .next()?
+        .unwrap_or_else(|e| panic!("Failed to read {}: {}", path.display(), e));
+    let (_, row) = year.parse_line(&line, &UserTable::new()).ok()?;
+    Some(row.timestamp)
+}
+
+/**
+ * Number of bytes read from the end of an uncompressed file to find the last line
+ */
+const TAIL_BYTES: u64 = 64 * 1024;
+
+/**
+ * Get the last data line of the file, None for compressed files which would have to be read through
+ */
+fn last_line(path: &Path) -> Option<String> {
+    if is_compressed(path) {
+        return None;
+    }
+    let mut file = File::open(path)
+        .unwrap_or_else(|e| panic!("Failed to open tile data {}: {}", path.display(), e));
+    let len = file.metadata()
+        .unwrap_or_else(|e| panic!("Failed to read {}: {}", path.display(), e))
+        .len();
+    let tail_len = len.min(TAIL_BYTES);
+    let mut tail = Vec::new();
+    file.seek(SeekFrom::Start(len - tail_len))
+        .and_then(|_| file.read_to_end(&mut tail))
+        .unwrap_or_else(|e| panic!("Failed to read {}: {}", path.display(), e));
+    let text = String::from_utf8_lossy(&tail);
+    let text = text.trim_end();
+    //Without a line break the whole file is the header
+    text.rfind('\n').map(|i| text[i + 1..].trim_end_matches('\r').to_string())
+}
+
+/**
+ * Get the timestamp of the last data line in the file
+ */
+fn last_timestamp(path: &Path, year: &PlaceDataSet) -> Option<PrimitiveDateTime> {
+    let line = last_line(path)?;
+    let (_, row) = year.parse_line(&line, &UserTable::new()).ok()?;
+    Some(row.timestamp)
+}
+
+/**
+ * Check that the file does not start before the last edit of the previous file
+ */
+pub(crate) fn check_file_boundary(previous: &Path, previous_end: PrimitiveDateTime, path: &Path, start: PrimitiveDateTime) -> Result<(), String> {
+    if start < previous_end {
+        return Err(format!("Tile data files overlap or are not in chronological order, {} starts before the last edit of {}",
+                           path.display(), previous.display()));
+    }
+    Ok(())
+}
+
+/**
+ * Rows with the lowest and highest position folded from a file with their edit time, used to check
+ * that the files of a split data set do not overlap while they are read
+ */
+#[derive(Default)]
+struct FileBounds {
+    first: Option<(u64, PrimitiveDateTime)>,
+    last: Option<(u64, PrimitiveDateTime)>,
+}
+
+impl FileBounds {
+    fn add(mut self, position: u64, time: PrimitiveDateTime) -> FileBounds {
+        if self.first.is_none_or(|(p, _)| position < p) {
+            self.first = Some((position, time));
+        }
+        if self.last.is_none_or(|(p, _)| position > p) {
+            self.last = Some((position, time));
+        }
+        self
+    }
+
+    fn merge(self, other: FileBounds) -> FileBounds {
+        let first = match (self.first, other.first) {
+            (Some(a), Some(b)) => { Some(if a.0 <= b.0 { a } else { b }) }
+            (a, b) => { a.or(b) }
+        };
+        let last = match (self.last, other.last) {
+            (Some(a), Some(b)) => { Some(if a.0 >= b.0 { a } else { b }) }
+            (a, b) => { a.or(b) }
+        };
+        FileBounds {
+            first,
+            last,
+        }
+    }
+}
+
+/**
+ * Number of rows given to a thread at a time when reading in parallel
+ */
+const CHUNK_ROWS: usize = 10000;
+
+/**
+ * Size of the parts memory mapped files are split to when reading in parallel
+ */
+const MAPPED_CHUNK_BYTES: usize = 8 * 1024 * 1024;
+
+/**
+ * Where the canvas history rows are read from
+ */
+enum DataSource {
+    Csv(Vec<PathBuf>, PlaceDataSet),
+    Cache(Cache),
+}
+
+/**
+ * Source of the canvas history rows, either the CSV files or the binary cache,
+ * together with the table of users seen in the rows
+ */
+pub(crate) struct TileData {
+    source: DataSource,
+    users: UserTable,
+    //Size of the pieces rows are given to threads in, smaller in tests so rows are split between threads
+    chunk_rows: usize,
+    mapped_chunk_bytes: usize,
+}
+
+impl TileData {
+    pub(crate) fn from_csv(files: Vec<PathBuf>, year: PlaceDataSet) -> TileData {
+        TileData {
+            source: DataSource::Csv(files, year),
+            users: UserTable::new(),
+            chunk_rows: CHUNK_ROWS,
+            mapped_chunk_bytes: MAPPED_CHUNK_BYTES,
+        }
+    }
+
+    pub(crate) fn from_cache(mut cache: Cache) -> TileData {
+        let users = UserTable::from_names(cache.take_users());
+        TileData {
+            source: DataSource::Cache(cache),
+            users,
+            chunk_rows: CHUNK_ROWS,
+            mapped_chunk_bytes: MAPPED_CHUNK_BYTES,
+        }
+    }
+
+    /**
+     * Use smaller chunks so the rows of small test data are split between many accumulators
+     */
+    #[cfg(test)]
+    pub(crate) fn with_chunk_sizes(mut self, rows: usize, bytes: usize) -> TileData {
+        self.chunk_rows = rows;
+        self.mapped_chunk_bytes = bytes;
+        self
+    }
+
+    /**
+     * Users seen in the rows, users of the CSV files are added while reading
+     */
+    pub(crate) fn users(&self) -> &UserTable {
+        &self.users
+    }
+
+    /**
+     * Data set the rows are from
+     */
+    pub(crate) fn year(&self) -> &PlaceDataSet {
+        match &self.source {
+            DataSource::Csv(_, year) => { year }
+            DataSource::Cache(cache) => { cache.year() }
+        }
+    }
+
+    /**
+     * Time of the first row of the data
+     */
+    pub(crate) fn first_edit_time(&self) -> Option<PrimitiveDateTime> {
+        match &self.source {
+            DataSource::Csv(files, year) => { first_timestamp(files.first()?, year) }
+            DataSource::Cache(cache) => { cache.row_time(0).ok() }
+        }
+    }
+
+    /**
+     * Time of the last row of the data, None if the last file is compressed since it would have to be read through
+     */
+    pub(crate) fn last_edit_time(&self) -> Option<PrimitiveDateTime> {
+        match &self.source {
+            DataSource::Csv(files, year) => { last_timestamp(files.last()?, year) }
+            DataSource::Cache(cache) => { cache.row_time(cache.row_count().checked_sub(1)?).ok() }
+        }
+    }
+
+    /**
+     * Fold the rows in parallel, rows are read in chunks and each thread folds the rows it is given
+     * to its own accumulator, accumulators are combined with the reduce function. Rows are not processed
+     * in order so the fold function is also given the position of the row, which grows in the order of
+     * the data. Rows of uncompressed files are placed by their byte offset and other rows by their line
+     */
+    pub(crate) fn par_fold<T, ID, F, R>(&self, identity: ID, fold: F, reduce: R) -> T
+        where T: Send,
+              ID: Fn() -> T + Send + Sync,
+              F: Fn(T, u64, &CanvasLine) -> T + Send + Sync,
+              R: Fn(T, T) -> T + Send + Sync {
+        match &self.source {
+            DataSource::Csv(files, year) => {
+                //Files are read one at a time, each file is read in parallel and its first and last edit
+                //are tracked so files that overlap are noticed without reading them separately
+                let bounded_identity = || (identity(), FileBounds::default());
+                let bounded_fold = |(acc, bounds): (T, FileBounds), position, row: &CanvasLine| {
+                    (fold(acc, position, row), bounds.add(position, row.timestamp))
+                };
+                let bounded_reduce = |(a, a_bounds): (T, FileBounds), (b, b_bounds): (T, FileBounds)| {
+                    (reduce(a, b), a_bounds.merge(b_bounds))
+                };
+                let mut first_position = 0;
+                let mut file_end: Option<(&PathBuf, PrimitiveDateTime)> = None;
+                files.iter().map(|path| {
+                    let ((acc, bounds), positions) = if is_compressed(path) {
+                        self.fold_stream(path, year, first_position, &bounded_identity, &bounded_fold, &bounded_reduce)
+                    } else {
+                        self.fold_mapped(path, year, first_position, &bounded_identity, &bounded_fold, &bounded_reduce)
+                    };
+                    if let (Some((previous, end)), Some((_, start))) = (file_end, bounds.first) {
+                        if let Err(e) = check_file_boundary(previous, end, path, start) {
+                            panic!("{}", e);
+                        }
+                    }
+                    if let Some((_, end)) = bounds.last {
+                        file_end = Some((path, end));
+                    }
+                    first_position += positions;
+                    acc
+                }).reduce(&reduce).unwrap_or_else(&identity)
+            }
+            DataSource::Cache(cache) => {
+                chunks(cache_rows(cache).enumerate(), self.chunk_rows).par_bridge().fold(&identity, |acc, chunk| {
+                    chunk.into_iter().fold(acc, |acc, (index, row_result)| {
+                        match row_result {
+                            Ok(row) => { fold(acc, index as u64, &row) }
+                            Err(e) => {
+                                warn!("Failed to read row from cache: {}", e);
+                                acc
+                            }
+                        }
+                    })
+                }).reduce(&identity, &reduce)
+            }
+        }
+    }
+
+    /**
+     * Fold the rows of a compressed file, lines are decompressed by one thread and parsed in parallel.
+     * Rows are placed by their line, returns the accumulator and the number of lines in the file
+     */
+    fn fold_stream<T, ID, F, R>(&self, path: &Path, year: &PlaceDataSet, first_position: u64, identity: &ID, fold: &F, reduce: &R) -> (T, u64)
+        where T: Send,
+              ID: Fn() -> T + Send + Sync,
+              F: Fn(T, u64, &CanvasLine) -> T + Send + Sync,
+              R: Fn(T, T) -> T + Send + Sync {
+        let files = [path.to_path_buf()];
+        let mut rows = 0;
+        let lines = tile_data_lines(&files).inspect(|_| rows += 1);
+        let acc = chunks(lines.enumerate(), self.chunk_rows).par_bridge().fold(identity, |acc, chunk| {
+            chunk.into_iter().fold(acc, |acc, (index, line_result)| {
+                match line_result {
+                    Ok(l) => {
+                        match parse_row(&l, year, &self.users) {
+                            Some(row) => { fold(acc, first_position + index as u64, &row) }
+                            None => { acc }
+                        }
+                    }
+                    Err(e) => {
+                        warn!("Failed to obtain line from tile data: {}", e);
+                        acc
+                    }
+                }
+            })
+        }).reduce(identity, reduce);
+        (acc, rows)
+    }
+
+    /**
+     * Fold the rows of an uncompressed file, the file is memory mapped and split to chunks at line breaks
+     * which are parsed in parallel without copying the lines. Rows are placed by their byte offset in the file,
+     * returns the accumulator and the size of the file
+     */
+    fn fold_mapped<T, ID, F, R>(&self, path: &Path, year: &PlaceDataSet, first_position: u64, identity: &ID, fold: &F, reduce: &R) -> (T, u64)
+        where T: Send,
+              ID: Fn() -> T + Send + Sync,
+              F: Fn(T, u64, &CanvasLine) -> T + Send + Sync,
+              R: Fn(T, T) -> T + Send + Sync {
+        let file = File::open(path)
+            .unwrap_or_else(|e| panic!("Failed to open tile data {}: {}", path.display(), e));
+        //Safety: the canvas history is not modified while it is being read
+        let data = unsafe { Mmap::map(&file) }
+            .unwrap_or_else(|e| panic!("Failed to map tile data {}: {}", path.display(), e));
+
+        //Skip the header
+        let start = match line_end(&data, 0) {
+            Some(end) => { end + 1 }
+            None => { panic!("Could not skip CSV header in {}", path.display()); }
+        };
+        let chunks = mapped_chunks(&data, start, self.mapped_chunk_bytes);
+
+        //Every thread folds a continuous part of the chunks so there is an accumulator per thread
+        let min_len = chunks.len().div_ceil(rayon::current_num_threads()).max(1);
+        let acc = chunks.into_par_iter().with_min_len(min_len).fold(identity, |acc, (chunk_start, chunk_end)| {
+            let mut acc = acc;
+            let mut line_start = chunk_start;
+            while line_start < chunk_end {
+                let end = line_end(&data[..chunk_end], line_start).unwrap_or(chunk_end);
+                let mut line = &data[line_start..end];
+                //Remove carriage return of Windows line endings
+                if let Some(stripped) = line.strip_suffix(b"\r") {
+                    line = stripped;
+                }
+                match std::str::from_utf8(line) {
+                    Ok(l) => {
+                        if !l.is_empty() {
+                            if let Some(parsed) = parse_row(l, year, &self.users) {
+                                acc = fold(acc, first_position + line_start as u64, &parsed);
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        warn!("Failed to obtain line from tile data: {}", e);
+                    }
+                }
+                line_start = end + 1;
+            }
+            acc
+        }).reduce(identity, reduce);
+        (acc, data.len() as u64)
+    }
+
+    /**
+     * Call the supplied function on every row in the order of the data
+     */
+    pub(crate) fn for_each<F>(&self, mut func: F)
+        where F: FnMut(&CanvasLine) {
+        match &self.source {
+            DataSource::Csv(files, year) => {
+                //Last edit read so far and the file it is from, files must not overlap
+                let mut file_end: Option<(&PathBuf, PrimitiveDateTime)> = None;
+                for (path, line_result) in files.iter().flat_map(|path| tile_data_lines(std::slice::from_ref(path)).map(move |l| (path, l))) {
+                    match line_result {
+                        Ok(l) => {
+                            if let Some(row) = parse_row(&l, year, &self.users) {
+                                if let Some((previous, end)) = file_end.filter(|(p, _)| *p != path) {
+                                    if let Err(e) = check_file_boundary(previous, end, path, row.timestamp) {
+                                        panic!("{}", e);
+                                    }
+                                }
+                                file_end = Some((path, row.timestamp));
+                                func(&row);
+                            }
+                        }
+                        Err(e) => {
+                            warn!("Failed to obtain line from tile data: {}", e);
+                        }
+                    };
+                }
+            }
+            DataSource::Cache(cache) => {
+                for row_result in cache_rows(cache) {
+                    match row_result {
+                        Ok(row) => { func(&row); }
+                        Err(e) => {
+                            warn!("Failed to read row from cache: {}", e);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/**
+ * Parse line of the canvas history, malformed lines are logged and skipped
+ */
+pub(crate) fn parse_row(line: &str, year: &PlaceDataSet, users: &UserTable) -> Option<CanvasLine> {
+    match year.parse_line(line, users) {
+        Ok((_, v)) => { Some(v) }
+        Err(_) => {
+            warn!("Malformed line in data: {}", line);
+            None
+        }
+    }
+}
+
+/**
+ * Index of the line break ending the line starting at the given index
+ */
+fn line_end(data: &[u8], start: usize) -> Option<usize> {
+    data[start..].iter().position(|b| *b == b'\n').map(|i| start + i)
+}
+
+/**
+ * Split the data to chunks of about the given size that end at line breaks
+ */
+fn mapped_chunks(data: &[u8], start: usize, chunk_bytes: usize) -> Vec<(usize, usize)> {
+    let mut chunks = Vec::new();
+    let mut chunk_start = start;
+    while chunk_start < data.len() {
+        let target = (chunk_start + chunk_bytes).min(data.len());
+        let chunk_end = match line_end(data, target.saturating_sub(1).max(chunk_start)) {
+            Some(end) => { end + 1 }
+            None => { data.len() }
+        };
+        chunks.push((chunk_start, chunk_end));
+        chunk_start = chunk_end;
+    }
+    chunks
+}
+
+/**
+ * Group the items to chunks so threads take work in larger pieces
+ */
+fn chunks<I: Iterator>(mut iter: I, chunk_rows: usize) -> impl Iterator<Item=Vec<I::Item>> {
+    std::iter::from_fn(move || {
+        let chunk: Vec<I::Item> = iter.by_ref().take(chunk_rows).collect();
+        (!chunk.is_empty()).then_some(chunk)
+    })
+}
+
+fn cache_rows(cache: &Cache) -> CacheRows<'_> {
+    cache.rows().expect("Failed to open cache columns")
+}
+
+/**
+ * File of a test in the temporary directory, the file is removed when this is dropped so it is not
+ * left behind when the test fails
+ */
+#[cfg(test)]
+pub(crate) struct TempFile {
+    pub(crate) path: PathBuf,
+}
+
+#[cfg(test)]
+impl TempFile {
+    /**
+     * Location for a temporary file with the name and extension, the file is not created
+     */
+    pub(crate) fn new(name: &str, extension: &str) -> TempFile {
+        TempFile {
+            path: std::env::temp_dir().join(format!("rplace_util_{}_{}.{}", name, std::process::id(), extension)),
+        }
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/**
+ * Write canvas history to a temporary file
+ */
+#[cfg(test)]
+pub(crate) fn write_csv(name: &str, content: &str) -> TempFile {
+    let file = TempFile::new(name, "csv");
+    fs::write(&file.path, content).expect("Failed to write test data");
+    file
+}
+
+/**
+ * Write gzip compressed canvas history to a temporary file
+ */
+#[cfg(test)]
+pub(crate) fn write_gz(name: &str, content: &str) -> TempFile {
+    use std::io::Write;
+    let file = TempFile::new(name, "csv.gz");
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+    encoder.write_all(content.as_bytes()).expect("Failed to compress test data");
+    fs::write(&file.path, encoder.finish().expect("Failed to compress test data"))
+        .expect("Failed to write test data");
+    file
+}
@@ -1,25 +1,35 @@
 use std::collections::{HashMap, HashSet};
 use std::{env, io};
 use std::fmt::{Display, Formatter};
-use std::fs::File;
-use std::io::{BufRead, BufReader, Write};
-use std::sync::{Arc, Mutex};
+use std::io::{BufRead, IsTerminal, Write};
+use std::path::Path;
 use config::Config;
 use env_logger::Env;
-use rayon::iter::ParallelBridge;
-use rayon::prelude::ParallelIterator;
 use log::{error, info, warn};
 use nom::{bytes, IResult};
 use nom::bytes::complete::{take_until};
 use nom::branch::alt;
 use nom::character::complete;
-use nom::combinator::eof;
+use nom::combinator::{eof, map_opt};
 use nom::sequence::{delimited, terminated};
 use serde::Deserialize;
 use time::format_description::FormatItem;
-use time::PrimitiveDateTime;
+use time::{OffsetDateTime, PrimitiveDateTime};
+use crate::area::{AreaShape, parse_colour};
+use crate::cache::Cache;
+use crate::canvas::{CanvasState, format_colour, SharedCanvasState};
+use crate::render::{Heatmap, Highlight, Render, Timelapse};
+use crate::input::{DataLocation, detect_year, TileData};
+use crate::users::{UserCounts, UserId, UserTable};
 
-#[derive(Eq, PartialEq, Hash, Deserialize, Clone)]
+mod area;
+mod cache;
+mod canvas;
+mod input;
+mod render;
+mod users;
+
+#[derive(Eq, PartialEq, Hash, Deserialize, Clone, Debug)]
 struct TileLocation {
     x: i16,
     y: i16,
@@ -42,7 +52,7 @@ impl TileLocation {
     }
 }
 
-#[derive(Eq, PartialEq, Hash, Deserialize, Clone)]
+#[derive(Eq, PartialEq, Hash, Deserialize, Clone, Debug)]
 struct TileRegion {
     top: i16,
     left: i16,
@@ -50,6 +60,12 @@ struct TileRegion {
     right: i16,
 }
 
+impl Display for TileRegion {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}, {} - {}, {}", self.left, self.top, self.right, self.bottom)
+    }
+}
+
 impl TileRegion {
     fn parse_line(input: &str) -> IResult<&str, LineCoordinate> {
         //1349,1718,1424,1752
@@ -85,19 +101,33 @@ impl TileRegion {
         true
     }
 
+    /**
+     * Iterate over the tiles in the region, edges are part of the region
+     */
+    fn points(&self) -> impl Iterator<Item=TileLocation> {
+        let (left, right) = (self.left, self.right);
+        (self.top..=self.bottom).flat_map(move |y| {
+            (left..=right).map(move |x| {
+                TileLocation {
+                    x,
+                    y,
+                }
+            })
+        })
+    }
+
+    /**
+     * Check if the regions share any tiles
+     */
     fn intersects(&self, region: &TileRegion) -> bool {
-        self.contains_point(region.left, region.top)
-            || self.contains_point(region.right, region.top)
-            || self.contains_point(region.right, region.bottom)
-            || self.contains_point(region.left, region.bottom)
-            || region.contains_point(self.left, self.top)
-            || region.contains_point(self.right, self.top)
-            || region.contains_point(self.right, self.bottom)
-            || region.contains_point(self.left, self.bottom)
+        self.left <= region.right
+            && region.left <= self.right
+            && self.top <= region.bottom
+            && region.top <= self.bottom
     }
 }
 
-#[derive(Eq, PartialEq, Hash, Deserialize, Clone)]
+#[derive(Eq, PartialEq, Hash, Deserialize, Clone, Debug)]
 struct TileCircle {
     x: i16,
     y: i16,
@@ -120,68 +150,54 @@ impl TileCircle {
         })))
     }
 
+    /**
+     * Check if the tile is inside the circle, tiles exactly at the radius are outside
+     */
     fn contains_point(&self, x: i16, y: i16) -> bool {
-        let x_dist = (x - self.x) as f64;
-        let y_dist = (y - self.y) as f64;
-        let dist = f64::sqrt((x_dist * x_dist) + (y_dist * y_dist));
-        dist < self.r as f64
+        let x_dist = x as i32 - self.x as i32;
+        let y_dist = y as i32 - self.y as i32;
+        let r = self.r as i32;
+        self.r > 0 && x_dist * x_dist + y_dist * y_dist < r * r
     }
 
+    /**
+     * Check if the circle and the region share any tiles
+     */
     fn intersects(&self, region: &TileRegion) -> bool {
-        self.contains_point(region.left, region.top)
-            || self.contains_point(region.right, region.top)
-            || self.contains_point(region.right, region.bottom)
-            || self.contains_point(region.left, region.bottom)
-            || region.contains_point(self.x + self.r, self.y + self.r)
-            || region.contains_point(self.x - self.r, self.y - self.r)
-            || region.contains_point(self.x + self.r, self.y - self.r)
-            || region.contains_point(self.x - self.r, self.y + self.r)
-    }
-
-    fn points(&self) -> Vec<TileLocation> {
-        let mut pixels: Vec<TileLocation> = Vec::new();
-
-        let mut x = self.r;
-        let mut y = 0;
-        let mut radius_error = 1 - x;
-
-        while x >= y
-        {
-            let mut start_x = -x + self.x;
-            let mut end_x = x + self.y;
-            self.line_points(&mut pixels, start_x, end_x, y + self.y);
-            if y != 0 {
-                self.line_points(&mut pixels, start_x, end_x, -y + self.y);
-            }
-            y += 1;
-
-            if radius_error < 0 {
-                radius_error += 2 * y + 1;
-            } else {
-                if x >= y {
-                    start_x = -y + 1 + self.x;
-                    end_x = y - 1 + self.x;
-                    self.line_points(&mut pixels, start_x, end_x, x + self.y);
-                    self.line_points(&mut pixels, start_x, end_x, -x + self.y);
-                }
-                x -= 1;
-                radius_error += 2 * (y - x + 1);
-            }
-        }
-        pixels
+        //Tile of the region closest to the centre of the circle
+        let x = self.x.clamp(region.left, region.right.max(region.left));
+        let y = self.y.clamp(region.top, region.bottom.max(region.top));
+        region.contains_point(x, y) && self.contains_point(x, y)
     }
 
-    fn line_points(&self, pixels: &mut Vec<TileLocation>, from_x: i16, to_x: i16, y: i16) {
-        for x in from_x..to_x {
-            pixels.push(TileLocation {
-                x,
-                y,
-            });
-        }
+    /**
+     * Iterate over the tiles inside the circle, same tiles that contains_point accepts
+     */
+    fn points(&self) -> impl Iterator<Item=TileLocation> {
+        let (centre_x, centre_y) = (self.x as i32, self.y as i32);
+        let r = (self.r as i32).max(0);
+        (-(r - 1)..=(r - 1)).flat_map(move |y_dist| {
+            //Largest horizontal distance with x_dist² + y_dist² < r²
+            let limit = r * r - y_dist * y_dist - 1;
+            let mut half_width = f64::sqrt(limit as f64) as i32;
+            while half_width * half_width > limit {
+                half_width -= 1;
+            }
+            while (half_width + 1) * (half_width + 1) <= limit {
+                half_width += 1;
+            }
+            let y = i16::try_from(centre_y + y_dist).ok();
+            (-half_width..=half_width).filter_map(move |x_dist| {
+                Some(TileLocation {
+                    x: i16::try_from(centre_x + x_dist).ok()?,
+                    y: y?,
+                })
+            })
+        })
     }
 }
 
-#[derive(Eq, PartialEq, Hash)]
+#[derive(Eq, PartialEq, Hash, Debug)]
 enum LineCoordinate {
     Tile(TileLocation),
     Region(TileRegion),
@@ -192,52 +208,150 @@ impl LineCoordinate {
     fn parse(input: &str) -> IResult<&str, LineCoordinate> {
         alt((TileRegion::parse_line, TileLocation::parse, TileCircle::parse_line))(input)
     }
+
+    /**
+     * Check if the edit changes any tile in the region
+     */
+    fn intersects(&self, region: &TileRegion) -> bool {
+        match self {
+            LineCoordinate::Tile(t) => { region.contains(t) }
+            LineCoordinate::Region(r) => { r.intersects(region) }
+            LineCoordinate::Circle(c) => { c.intersects(region) }
+        }
+    }
+
+    /**
+     * Call the function on every tile the edit changes
+     */
+    fn for_each_point<F>(&self, mut func: F)
+        where F: FnMut(TileLocation) {
+        match self {
+            LineCoordinate::Tile(t) => { func(t.clone()) }
+            LineCoordinate::Region(r) => { r.points().for_each(func) }
+            LineCoordinate::Circle(c) => { c.points().for_each(func) }
+        }
+    }
 }
 
+#[derive(PartialEq, Debug)]
 struct CanvasLine {
-    timestamp: String,
-    user_id: String,
-    pixel_color: String,
+    timestamp: PrimitiveDateTime,
+    user_id: UserId,
+    pixel_color: [u8; 3],
     coordinate: LineCoordinate,
 }
 
 impl CanvasLine {
-    fn parse2022(input: &str) -> IResult<&str, CanvasLine> {
+    fn parse2022<'a>(input: &'a str, users: &UserTable) -> IResult<&'a str, CanvasLine> {
         //2022-04-04 00:55:57.168 UTC,tPcrtm7OtEmSThdRSWmB7jmTF9lUVZ1pltNv1oKqPY9bom/EGIO3/b5kjRenbD3vMF48psnR9MnhIrTT1bpC9A==,#6A5CFF,"1908,1854"
-        let (input, timestamp) = terminated(take_until(","), complete::char(','))(input)?;
+        let (input, timestamp) = terminated(map_opt(take_until(","), parse_timestamp), complete::char(','))(input)?;
         //tPcrtm7OtEmSThdRSWmB7jmTF9lUVZ1pltNv1oKqPY9bom/EGIO3/b5kjRenbD3vMF48psnR9MnhIrTT1bpC9A==,#6A5CFF,"1908,1854"
         let (input, user_id) = terminated(take_until(","), complete::char(','))(input)?;
         //#6A5CFF,"1908,1854"
-        let (input, pixel_color) = terminated(take_until(","), complete::char(','))(input)?;
+        let (input, pixel_color) = terminated(map_opt(take_until(","), parse_colour), complete::char(','))(input)?;
         //"1908,1854" or "1349,1718,1424,1752"
         let (input, coordinate) = delimited(complete::char('"'), LineCoordinate::parse, complete::char('"'))(input)?;
 
         Ok((input, CanvasLine {
-            timestamp: timestamp.to_string(),
-            user_id: user_id.to_string(),
-            pixel_color: pixel_color.to_string(),
+            timestamp,
+            user_id: users.intern(user_id),
+            pixel_color,
             coordinate,
         }))
     }
-    fn parse2023(input: &str) -> IResult<&str, CanvasLine> {
+    fn parse2017<'a>(input: &'a str, users: &UserTable) -> IResult<&'a str, CanvasLine> {
+        //2017 palette, edits refer to the colours by index
+        const PALETTE_2017: [[u8; 3]; 16] = [
+            [0xFF, 0xFF, 0xFF], [0xE4, 0xE4, 0xE4], [0x88, 0x88, 0x88], [0x22, 0x22, 0x22],
+            [0xFF, 0xA7, 0xD1], [0xE5, 0x00, 0x00], [0xE5, 0x95, 0x00], [0xA0, 0x6A, 0x42],
+            [0xE5, 0xD9, 0x00], [0x94, 0xE0, 0x44], [0x02, 0xBE, 0x01], [0x00, 0xD3, 0xDD],
+            [0x00, 0x83, 0xC7], [0x00, 0x00, 0xEA], [0xCF, 0x6E, 0xE4], [0x82, 0x00, 0x80],
+        ];
+
+        //1490918688000,ovTZk4GyTS1mDQnTbV+vDOCu1f+u6w+CkIZ6445vD4XN8alFy/6GtNkYp5MSic6Tjo/fBCCGe6oZKMAN3rEZHw==,162,147,2
+        //Time is either milliseconds since unix epoch or in the format of the later data sets
+        let (input, timestamp) = terminated(map_opt(take_until(","), |t: &str| {
+            match t.parse::<i64>() {
+                Ok(millis) => { from_millis(millis) }
+                Err(_) => { parse_timestamp(t) }
+            }
+        }), complete::char(','))(input)?;
+        //ovTZk4GyTS1mDQnTbV+vDOCu1f+u6w+CkIZ6445vD4XN8alFy/6GtNkYp5MSic6Tjo/fBCCGe6oZKMAN3rEZHw==,162,147,2
+        let (input, user_id) = terminated(take_until(","), complete::char(','))(input)?;
+        //162,147,2
+        let (input, x) = terminated(complete::i16, complete::char(','))(input)?;
+        let (input, y) = terminated(complete::i16, complete::char(','))(input)?;
+        let (input, pixel_color) = terminated(
+            map_opt(complete::u8, |i| PALETTE_2017.get(i as usize).copied()), eof)(input)?;
+
+        Ok((input, CanvasLine {
+            timestamp,
+            user_id: users.intern(user_id),
+            pixel_color,
+            coordinate: LineCoordinate::Tile(TileLocation {
+                x,
+                y,
+            }),
+        }))
+    }
+    fn parse2023<'a>(input: &'a str, users: &UserTable) -> IResult<&'a str, CanvasLine> {
         //2023-07-20 18:55:57.168 UTC,tPcrtm7OtEmSThdRSWmB7jmTF9lUVZ1pltNv1oKqPY9bom/EGIO3/b5kjRenbD3vMF48psnR9MnhIrTT1bpC9A==,"1908,1854",#6A5CFF
-        let (input, timestamp) = terminated(take_until(","), complete::char(','))(input)?;
+        let (input, timestamp) = terminated(map_opt(take_until(","), parse_timestamp), complete::char(','))(input)?;
         //tPcrtm7OtEmSThdRSWmB7jmTF9lUVZ1pltNv1oKqPY9bom/EGIO3/b5kjRenbD3vMF48psnR9MnhIrTT1bpC9A==,"1908,1854",#6A5CFF
         let (input, user_id) = terminated(take_until(","), complete::char(','))(input)?;
         //"1908,1854" or "1349,1718,1424,1752" or "{X: 481, Y: 416, R: 3}"
         let (input, coordinate) = delimited(complete::char('"'), LineCoordinate::parse, complete::char('"'))(input)?;
         //#6A5CFF
-        let (input, pixel_color) = delimited(complete::char(','), bytes::complete::take(7usize), eof)(input)?;
+        let (input, pixel_color) = delimited(complete::char(','), map_opt(bytes::complete::take(7usize), parse_colour), eof)(input)?;
 
         Ok((input, CanvasLine {
-            timestamp: timestamp.to_string(),
-            user_id: user_id.to_string(),
-            pixel_color: pixel_color.to_string(),
+            timestamp,
+            user_id: users.intern(user_id),
+            pixel_color,
             coordinate,
         }))
     }
 }
 
+/**
+ * Parse timestamp used in the canvas history, fractional seconds are left out when zero
+ */
+fn parse_timestamp(timestamp: &str) -> Option<PrimitiveDateTime> {
+    const RPLACE_TIME_FORMAT: &[FormatItem] = time::macros::format_description!("[year]-[month]-[day] [hour]:[minute]:[second].[subsecond] UTC");
+    const RPLACE_TIME_FORMAT_SHORT: &[FormatItem] = time::macros::format_description!("[year]-[month]-[day] [hour]:[minute]:[second] UTC");
+
+    PrimitiveDateTime::parse(timestamp, RPLACE_TIME_FORMAT)
+        .or_else(|_| {
+            PrimitiveDateTime::parse(timestamp, RPLACE_TIME_FORMAT_SHORT)
+        })
+        .ok()
+}
+
+/**
+ * Convert time of the canvas history to milliseconds since unix epoch
+ */
+fn to_millis(time: PrimitiveDateTime) -> i64 {
+    (time.assume_utc().unix_timestamp_nanos() / 1_000_000) as i64
+}
+
+/**
+ * Convert milliseconds since unix epoch to time
+ */
+fn from_millis(millis: i64) -> Option<PrimitiveDateTime> {
+    let time = OffsetDateTime::from_unix_timestamp_nanos(millis as i128 * 1_000_000).ok()?;
+    Some(PrimitiveDateTime::new(time.date(), time.time()))
+}
+
+/**
+ * Format time in the format used in the canvas history
+ */
+fn format_timestamp(time: &PrimitiveDateTime) -> String {
+    const RPLACE_TIME_FORMAT_MILLIS: &[FormatItem] = time::macros::format_description!("[year]-[month]-[day] [hour]:[minute]:[second].[subsecond digits:3] UTC");
+
+    time.format(RPLACE_TIME_FORMAT_MILLIS)
+        .expect("Failed to format time")
+}
+
 time::serde::format_description!(rplace_time_format, PrimitiveDateTime, "[year]-[month]-[day] [hour]:[minute]:[second].[subsecond] UTC");
 
 #[derive(Deserialize, Clone)]
@@ -248,23 +362,27 @@ struct SearchArea {
     end_time: Option<PrimitiveDateTime>,
     #[serde(default)]
     is_optional: bool,
-    #[serde(default)]
-    colours: Vec<String>,
-    area: TileRegion,
+    #[serde(default, deserialize_with = "deserialize_colours")]
+    colours: Vec<[u8; 3]>,
+    area: AreaShape,
+}
+
+/**
+ * Parse the colours of the search area, colours are given in the hex format of the canvas history
+ */
+fn deserialize_colours<'de, D>(deserializer: D) -> Result<Vec<[u8; 3]>, D::Error>
+    where D: serde::Deserializer<'de> {
+    let colours: Vec<String> = Vec::deserialize(deserializer)?;
+    colours.iter().map(|c| {
+        parse_colour(c).ok_or_else(|| serde::de::Error::custom(format!("Invalid colour {}, expected format like #FF4500", c)))
+    }).collect()
 }
 
 impl SearchArea {
     fn contains(&self, pixel: &CanvasLine) -> bool {
-        const RPLACE_TIME_FORMAT: &[FormatItem] = time::macros::format_description!("[year]-[month]-[day] [hour]:[minute]:[second].[subsecond] UTC");
-        const RPLACE_TIME_FORMAT_SHORT: &[FormatItem] = time::macros::format_description!("[year]-[month]-[day] [hour]:[minute]:[second] UTC");
-
-        let line_time = PrimitiveDateTime::parse(&pixel.timestamp, RPLACE_TIME_FORMAT)
-            .or_else(|_| {
-                PrimitiveDateTime::parse(&pixel.timestamp, RPLACE_TIME_FORMAT_SHORT)
-            })
-            .unwrap_or_else(|_| panic!("Can not parse: {} Malformed time in CSV", &pixel.timestamp));
+        let line_time = pixel.timestamp;
         if let Some(start_time) = self.start_time {
-            if start_time < line_time {
+            if line_time < start_time {
                 return false;
             }
         }
@@ -277,33 +395,111 @@ impl SearchArea {
             return false;
         }
 
-        match &pixel.coordinate {
-            LineCoordinate::Tile(t) => {
-                self.area.contains(t)
-            }
-            LineCoordinate::Region(r) => {
-                self.area.intersects(r)
-            }
-            LineCoordinate::Circle(c) => {
-                c.intersects(&self.area)
-            }
-        }
+        self.area.matches_edit(pixel)
     }
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Clone, PartialEq, Debug)]
 enum PlaceDataSet {
     Place2023,
     Place2022,
+    Place2017,
+}
+
+impl PlaceDataSet {
+    /**
+     * Parse line of the canvas history using the format of the data set
+     */
+    fn parse_line<'a>(&self, input: &'a str, users: &UserTable) -> IResult<&'a str, CanvasLine> {
+        match self {
+            PlaceDataSet::Place2023 => { CanvasLine::parse2023(input, users) }
+            PlaceDataSet::Place2022 => { CanvasLine::parse2022(input, users) }
+            PlaceDataSet::Place2017 => { CanvasLine::parse2017(input, users) }
+        }
+    }
+
+    /**
+     * Time when the whiteout started, edits before it are on the final image
+     */
+    fn whiteout_start(&self) -> Option<PrimitiveDateTime> {
+        match self {
+            PlaceDataSet::Place2023 => { Some(time::macros::datetime!(2023-07-25 21:44:00)) }
+            PlaceDataSet::Place2022 => { Some(time::macros::datetime!(2022-04-04 22:47:40)) }
+            //There was no whiteout in 2017, final image is the end
+            PlaceDataSet::Place2017 => { None }
+        }
+    }
+
+    /**
+     * Area of the canvas at the end of the event
+     */
+    fn canvas(&self) -> TileRegion {
+        match self {
+            PlaceDataSet::Place2023 => {
+                TileRegion {
+                    left: -1500,
+                    top: -1000,
+                    right: 1499,
+                    bottom: 999,
+                }
+            }
+            PlaceDataSet::Place2022 => {
+                TileRegion {
+                    left: 0,
+                    top: 0,
+                    right: 1999,
+                    bottom: 1999,
+                }
+            }
+            PlaceDataSet::Place2017 => {
+                TileRegion {
+                    left: 0,
+                    top: 0,
+                    right: 999,
+                    bottom: 999,
+                }
+            }
+        }
+    }
 }
 
 #[derive(Deserialize)]
 struct Settings {
     user_id: Option<String>,
-    csv_location: String,
-    year: PlaceDataSet,
+    csv_location: DataLocation,
+    cache_location: Option<String>,
+    year: Option<PlaceDataSet>,
+    #[serde(with = "rplace_time_format::option", default)]
+    final_image_time: Option<PrimitiveDateTime>,
+    #[serde(with = "rplace_time_format::option", default)]
+    survival_time: Option<PrimitiveDateTime>,
+    #[serde(default)]
     search_areas: Vec<SearchArea>,
     no_edits_outside: bool,
+    min_score: f64,
+    max_candidates: Option<usize>,
+    selection: Selection,
+    single_scan: bool,
+    render: Option<Render>,
+    timelapse: Option<Timelapse>,
+    highlight: Option<Highlight>,
+    heatmap: Option<Heatmap>,
+}
+
+/**
+ * How users are selected when there are multiple potential users
+ */
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Selection {
+    //Ask which user to analyse
+    Interactive,
+    //Analyse all potential users
+    All,
+    //Analyse the best scoring user
+    Best,
+    //Only show the potential users
+    List,
 }
 
 fn main() {
@@ -314,431 +510,1203 @@ fn main() {
         })
         .init();
 
-    //Get config file location from command line
-    let has_config_path;
-    let args: Vec<String> = env::args().collect();
-    let config_path = if args.len() > 1 {
-        has_config_path = true;
-        &args[1]
-    } else {
-        has_config_path = false;
-        "config.toml"
-    };
+    //Get config file location and selection mode from command line
+    let mut config_path = None;
+    let mut cli_selection = None;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--select" {
+            cli_selection = Some(args.next().expect("Missing selection mode after --select"));
+        } else if let Some(mode) = arg.strip_prefix("--select=") {
+            cli_selection = Some(mode.to_string());
+        } else if arg.starts_with("--") {
+            panic!("Unknown argument {}, usage: rplace_util [config file] [--select interactive|all|best|list]", arg);
+        } else {
+            config_path = Some(arg);
+        }
+    }
+    let has_config_path = config_path.is_some();
+    let config_path = config_path.unwrap_or_else(|| "config.toml".to_string());
 
     let config = Config::builder()
         .set_default("csv_location", "2022_place_canvas_history.csv")
         .expect("Failed to set default csv location")
         .set_default("no_edits_outside", true)
         .expect("Failed to set default no_edits_outside")
-        .add_source(config::File::with_name(config_path).required(has_config_path))
+        .set_default("min_score", 1.0)
+        .expect("Failed to set default min_score")
+        .set_default("selection", "interactive")
+        .expect("Failed to set default selection")
+        .set_default("single_scan", false)
+        .expect("Failed to set default single_scan")
+        .add_source(config::File::with_name(&config_path).required(has_config_path))
+        .set_override_option("selection", cli_selection)
+        .expect("Failed to set selection from command line")
         .build()
         .expect("Configuration file contains errors");
     let settings: Settings = config.try_deserialize()
         .expect("Failed to parse configurations");
 
+    //Read the canvas history from the cache when it is available
+    let tile_data = match &settings.cache_location {
+        Some(cache_location) => {
+            let cache_path = Path::new(cache_location);
+            let files = settings.csv_location.files();
+            if !cache_path.exists() {
+                //Order of the files is checked while converting
+                let year = detect_year(&files, settings.year.as_ref());
+                cache::convert(&files, &year, cache_path)
+                    .expect("Failed to convert tile data to cache");
+            }
+            let cache = Cache::open(cache_path)
+                .expect("Failed to open tile data cache");
+            if let Err(e) = cache.check_sources(&files) {
+                panic!("Cache {} does not match csv_location, {}, remove the cache to convert the tile data again",
+                       cache_location, e);
+            }
+            if let Some(year) = &settings.year {
+                if cache.year() != year {
+                    panic!("Configured year is {:?} but cache {} was created from {:?}",
+                           year, cache_location, cache.year());
+                }
+            }
+            TileData::from_cache(cache)
+        }
+        None => {
+            let files = settings.csv_location.files();
+            //Order of the files is checked while reading them
+            let year = detect_year(&files, settings.year.as_ref());
+            TileData::from_csv(files, year)
+        }
+    };
+    let year = tile_data.year();
+    info!("Using data set {:?}", year);
+
+    //Warn about search areas that can not contain any edits
+    let canvas = year.canvas();
+    for location in &settings.search_areas {
+        if !location.area.intersects(&canvas) {
+            warn!("Search area {} is outside of the canvas", location.area);
+        }
+    }
+
+    if let Some(render) = &settings.render {
+        render::render_canvas(&tile_data, render, &canvas);
+    }
+    if let Some(timelapse) = &settings.timelapse {
+        render::timelapse(&tile_data, timelapse, &canvas);
+    }
+    if let Some(heatmap) = &settings.heatmap {
+        render::heatmap(&tile_data, heatmap, &canvas);
+    }
+    //Images do not need a user
+    if settings.user_id.is_none() && settings.search_areas.is_empty() {
+        return;
+    }
+
     //If we do not have a user id try to find user from specified areas
-    let settings_user = settings.user_id.clone();
-    let userid = settings_user.or_else(|| {
-        find_user(&settings)
+    let settings_user = settings.user_id.as_ref().map(|u| {
+        tile_data.users().intern(u)
     });
-    //Check if we have a user id
-    if let Some(user) = userid {
-        info!("Finding tiles that remain");
-        find_remaining_tiles(&user, &settings.csv_location, &settings.year);
+    let final_image_time = settings.final_image_time.or_else(|| year.whiteout_start());
+    let (user_ids, single_scan) = match settings_user {
+        Some(user) => { (vec![user], None) }
+        None => {
+            //When enabled the last edit of every tile is tracked while searching so survival does not need another scan
+            let last_writers = settings.single_scan.then(|| {
+                LastWriters::new(canvas.clone(), final_image_time, settings.survival_time)
+            });
+            let mut result = search_edits(&settings, &tile_data, last_writers.as_ref());
+            let user_ids = find_users(&settings, &tile_data, result.candidates());
+            (user_ids, last_writers.map(|l| (l.into_canvas(), result.tiles_placed)))
+        }
+    };
+    //Highlighting needs the whole canvas, replay the area unless it was tracked while searching
+    let highlight_canvas = match (&settings.highlight, &single_scan) {
+        (Some(highlight), None) if !user_ids.is_empty() => {
+            let region = highlight.region(&canvas);
+            info!("Replaying edits in {}", region);
+            let last_writers = LastWriters::new(region.clone(), final_image_time, settings.survival_time);
+            tile_data.par_fold(|| (), |_, position, row| {
+                if row.coordinate.intersects(&region) {
+                    last_writers.apply(position, row);
+                }
+            }, |_, _| ());
+            Some(last_writers.into_canvas())
+        }
+        _ => { None }
+    };
+    let show_user = user_ids.len() > 1;
+    for (index, user) in user_ids.into_iter().enumerate() {
+        let surviving = match &single_scan {
+            Some((tracked, tiles_placed)) => { tracked.surviving_tiles(user, tiles_placed.get(user)) }
+            None => {
+                info!("Finding tiles that remain");
+                find_remaining_tiles(user, &tile_data, &canvas, final_image_time, settings.survival_time)
+            }
+        };
+        if show_user {
+            println!("Tiles of user {}:", tile_data.users().name(user));
+        }
+        print_remaining_tiles(&surviving, settings.survival_time);
+        let tracked = highlight_canvas.as_ref().or(single_scan.as_ref().map(|(tracked, _)| tracked));
+        if let (Some(highlight), Some(states)) = (&settings.highlight, tracked) {
+            render::highlight_user(states.final_image(), &states.end, user, highlight, &canvas, show_user.then_some(index));
+        }
     }
 }
 
-fn find_user(settings: &Settings) -> Option<String> {
-    //HashMap of users who have edits in selected areas
-    let users = Arc::new(Mutex::new(
-        HashMap::<String, HashSet<TileRegion>>::new()));
+/**
+ * Edits of a potential user in the search areas
+ */
+#[derive(Default, Clone)]
+struct Candidate {
+    //Search areas user has edits in
+    areas: HashSet<usize>,
+    //Number of edits matching any search area
+    edits_inside: u64,
+    //Number of edits outside all search areas, counted separately and added after the search
+    edits_outside: u64,
+}
 
-    //Get list of potential users in selected areas
-    let locations = &settings.search_areas;
-    info!("Finding users who have edits in selected areas");
-    mutate_user_list(add_internal_edits, locations, &settings.csv_location, users.clone(), &settings.year);
-    match users.lock() {
-        Ok(g) => {
-            info!("Total users in selected area {}", g.len())
-        }
-        Err(e) => {
-            eprintln!("Mutex lock failed: {}", e);
-        }
+/**
+ * Edit of a user as seen by the search
+ */
+enum CandidateEdit {
+    //Edit matches the search areas with these indexes
+    Inside(Vec<usize>),
+    //Edit is outside all search areas
+    Outside,
+}
+
+impl Candidate {
+    fn apply(&mut self, areas: Vec<usize>) {
+        self.areas.extend(areas);
+        self.edits_inside += 1;
     }
 
-    //If enabled remove users who have edits outside selected areas
-    if settings.no_edits_outside {
-        info!("Removing users who have edits outside selected areas");
-        mutate_user_list(remove_external_edits, locations, &settings.csv_location, users.clone(), &settings.year);
-        match users.lock() {
-            Ok(g) => {
-                info!("After removing users who have edits outside selected areas {}", g.len())
-            }
-            Err(e) => {
-                eprintln!("Mutex lock failed: {}", e);
-            }
-        }
+    fn merge(&mut self, other: Candidate) {
+        self.areas.extend(other.areas);
+        self.edits_inside += other.edits_inside;
+        self.edits_outside += other.edits_outside;
     }
 
+    /**
+     * Score between 0 and 1, fraction of the required areas user has edits in multiplied by
+     * the fraction of edits of the user that are inside the search areas
+     */
+    fn score(&self, required_areas: &HashSet<usize>) -> f64 {
+        let area_fraction = if required_areas.is_empty() {
+            1.0
+        } else {
+            self.areas.intersection(required_areas).count() as f64 / required_areas.len() as f64
+        };
+        let edits = self.edits_inside + self.edits_outside;
+        let inside_fraction = if edits == 0 {
+            0.0
+        } else {
+            self.edits_inside as f64 / edits as f64
+        };
+        area_fraction * inside_fraction
+    }
+}
+
+/**
+ * Potential user with the score used to rank the users
+ */
+struct RankedUser {
+    user: UserId,
+    score: f64,
+    candidate: Candidate,
+}
+
+/**
+ * Rank the users by score, best match first, users scoring below the minimum are removed
+ */
+fn rank_candidates(candidates: HashMap<UserId, Candidate>, locations: &[SearchArea], min_score: f64, users: &UserTable) -> Vec<RankedUser> {
     //Set of search areas that user must be present in
-    let required_ares: HashSet<TileRegion> = locations.iter().filter(|a| {
+    let required_areas: HashSet<usize> = locations.iter().enumerate().filter(|(_, a)| {
         !a.is_optional
-    }).map(|r| {
-        r.area.clone()
+    }).map(|(index, _)| {
+        index
     }).collect();
 
-    //Remove uses who did not have edits in all selected areas
-    let user = match users.lock() {
-        Ok(mut g) => {
-            //Remove elements which were not found in all selected areas
-            info!("Removing users who do not have edits in all selected areas");
-            g.retain(|_, regions| {
-                regions.is_superset(&required_ares)
-            });
-            let potential_users: Vec<String> = g.clone().into_keys().collect();
-            if potential_users.is_empty() {
-                println!("Did not find any users.");
-                return None;
-            }
+    let mut ranked: Vec<RankedUser> = candidates.into_iter().map(|(user, candidate)| {
+        RankedUser {
+            user,
+            score: candidate.score(&required_areas),
+            candidate,
+        }
+    }).filter(|r| {
+        r.score >= min_score
+    }).collect();
+    //Ties are ordered by the number of edits and then by the hashed user id, ids given to users
+    //depend on the order threads read the rows so they can not keep the order stable between runs
+    ranked.sort_by(|a, b| {
+        b.score.total_cmp(&a.score)
+            .then(b.candidate.edits_inside.cmp(&a.candidate.edits_inside))
+            .then_with(|| users.name(a.user).cmp(&users.name(b.user)))
+    });
+    ranked
+}
 
-            println!("Found users:");
-            for (index, user) in potential_users.iter().enumerate() {
-                println!("{}: {}", index, user);
-            }
+/**
+ * Select the users to analyse from the users who have edits in the search areas
+ */
+fn find_users(settings: &Settings, tile_data: &TileData, candidates: HashMap<UserId, Candidate>) -> Vec<UserId> {
+    let locations = &settings.search_areas;
+    info!("Total users in selected area {}", candidates.len());
 
-            let input;
-            if potential_users.len() > 1 {
-                print!("Select user by giving index: ");
-                if let Err(e) = io::stdout().flush() {
-                    error!("Failed to flush stdout: {}", e);
-                }
-                loop {
-                    let mut user_input = String::new();
-                    if let Err(e) = io::stdin().read_line(&mut user_input) {
-                        error!("Failed to read input: {}", e);
-                        continue;
-                    }
-                    //Remove trailing newline
-                    user_input.pop();
-                    let user_index: usize = match user_input.parse() {
-                        Ok(v) => {
-                            if v >= potential_users.len() {
-                                eprintln!("Index out of bounds");
-                                continue;
-                            }
-                            v
-                        }
-                        Err(_) => {
-                            eprintln!("Give zero based index of user you want to select");
-                            continue;
-                        }
-                    };
-                    input = user_index;
-                    break;
-                }
-            } else {
-                input = 0;
-            }
+    //Rank users by how well they match the search areas
+    let required_count = locations.iter().filter(|a| !a.is_optional).count();
+    let mut potential_users = rank_candidates(candidates, locations, settings.min_score, tile_data.users());
+    if let Some(max_candidates) = settings.max_candidates {
+        potential_users.truncate(max_candidates);
+    }
+    if potential_users.is_empty() {
+        println!("Did not find any users.");
+        return Vec::new();
+    }
 
-            Some(potential_users[input].clone())
-        }
-        Err(e) => {
-            eprintln!("Mutex lock failed: {}", e);
-            None
-        }
-    };
-    user
+    println!("Found users:");
+    for (index, ranked) in potential_users.iter().enumerate() {
+        let required_hit = ranked.candidate.areas.iter().filter(|a| !locations[**a].is_optional).count();
+        println!("{}: {} score: {:.3}, required areas: {}/{}, edits inside: {}, edits outside: {}",
+                 index, tile_data.users().name(ranked.user), ranked.score, required_hit, required_count,
+                 ranked.candidate.edits_inside, ranked.candidate.edits_outside);
+    }
+
+    let stdin = io::stdin();
+    let is_terminal = stdin.is_terminal();
+    select_users(&potential_users, &settings.selection, &mut stdin.lock(), is_terminal)
 }
 
 /**
- * Add users who have edits inside selected areas to the HashMap
+ * Select the users to analyse from the ranked potential users, interactive selection reads the index
+ * of the user from input
  */
-fn add_internal_edits(users: Arc<Mutex<HashMap<String, HashSet<TileRegion>>>>, line: &str, locations: &Vec<SearchArea>, year: &PlaceDataSet) {
-    //Convert line to struct
-    let row_result = match year {
-        PlaceDataSet::Place2023 => {
-            match CanvasLine::parse2023(line) {
-                Ok((_, v)) => { v }
-                Err(_) => {
-                    warn!("Malformed line in data: {}", line);
-                    return;
-                }
+fn select_users<R: BufRead>(potential_users: &[RankedUser], selection: &Selection, input: &mut R, is_terminal: bool) -> Vec<UserId> {
+    match selection {
+        Selection::All => { return potential_users.iter().map(|r| r.user).collect(); }
+        Selection::Best => { return vec![potential_users[0].user]; }
+        Selection::List => { return Vec::new(); }
+        Selection::Interactive => {}
+    }
+    if potential_users.len() == 1 {
+        return vec![potential_users[0].user];
+    }
+
+    //Without a terminal the index is read from the piped input once instead of asking again
+    print!("Select user by giving index: ");
+    if let Err(e) = io::stdout().flush() {
+        error!("Failed to flush stdout: {}", e);
+    }
+    loop {
+        let mut user_input = String::new();
+        match input.read_line(&mut user_input) {
+            Ok(0) => {
+                eprintln!("No user selected, input ended");
+                return Vec::new();
             }
-        }
-        PlaceDataSet::Place2022 => {
-            match CanvasLine::parse2022(line) {
-                Ok((_, v)) => { v }
-                Err(_) => {
-                    warn!("Malformed line in data: {}", line);
-                    return;
+            Ok(_) => {}
+            Err(e) => {
+                error!("Failed to read input: {}", e);
+                if is_terminal {
+                    continue;
                 }
+                return Vec::new();
             }
         }
-    };
-
-    //Check if coordinates in selected areas
-    for location in locations {
-        //Check if search area matches the line
-        if !location.contains(&row_result) {
-            continue;
-        }
-        //Matches, add area to the set of areas user has placed pixels in
-        match users.lock() {
-            Ok(mut g) => {
-                let region_set = g.entry(row_result.user_id.clone())
-                    .or_insert_with(|| { HashSet::<TileRegion>::new() });
-                region_set.insert(location.area.clone());
+        match user_input.trim().parse::<usize>() {
+            Ok(v) if v < potential_users.len() => {
+                return vec![potential_users[v].user];
             }
-            Err(e) => {
-                eprintln!("Mutex lock failed: {}", e);
+            Ok(_) => {
+                eprintln!("Index out of bounds");
+            }
+            Err(_) => {
+                eprintln!("Give zero based index of user you want to select");
             }
         }
+        if !is_terminal {
+            return Vec::new();
+        }
     }
 }
 
 /**
- * Remove users who have edits outside selected areas from the HashMap
+ * Edits of the users in the search areas, and the number of tiles every user placed when the
+ * last edit of every tile is tracked
  */
-fn remove_external_edits(users: Arc<Mutex<HashMap<String, HashSet<TileRegion>>>>, line: &str, locations: &Vec<SearchArea>, year: &PlaceDataSet) {
-    let row_result = match year {
-        PlaceDataSet::Place2023 => {
-            match CanvasLine::parse2023(line) {
-                Ok((_, v)) => { v }
-                Err(_) => {
-                    warn!("Malformed line in data: {}", line);
-                    return;
-                }
-            }
-        }
-        PlaceDataSet::Place2022 => {
-            match CanvasLine::parse2022(line) {
-                Ok((_, v)) => { v }
-                Err(_) => {
-                    warn!("Malformed line in data: {}", line);
-                    return;
-                }
-            }
+struct SearchResult {
+    //Users with edits in the search areas
+    candidates: HashMap<UserId, Candidate>,
+    //Number of edits outside the search areas, nearly every user has them so they are not kept in the map
+    edits_outside: UserCounts,
+    tiles_placed: UserCounts,
+}
+
+impl SearchResult {
+    /**
+     * Users with edits in the search areas together with their number of edits outside the areas
+     */
+    fn candidates(&mut self) -> HashMap<UserId, Candidate> {
+        let mut candidates = std::mem::take(&mut self.candidates);
+        for (user, candidate) in candidates.iter_mut() {
+            candidate.edits_outside = self.edits_outside.get(*user);
         }
-    };
+        candidates
+    }
 
-    //Remove users who have edits outside locations
-    let mut is_outside = true;
-    for location in locations {
-        match &row_result.coordinate {
-            LineCoordinate::Tile(t) => {
-                if location.area.contains(t) {
-                    is_outside = false;
-                    break;
-                }
-            }
-            LineCoordinate::Region(r) => {
-                if location.area.intersects(r) {
-                    is_outside = false;
-                    break;
-                }
-            }
-            LineCoordinate::Circle(c) => {
-                if c.intersects(&location.area) {
-                    is_outside = false;
-                    break;
-                }
-            }
+    fn merge(self, other: SearchResult) -> SearchResult {
+        //Merge the smaller map into the larger one
+        let (mut candidates, smaller) = if self.candidates.len() >= other.candidates.len() {
+            (self.candidates, other.candidates)
+        } else {
+            (other.candidates, self.candidates)
+        };
+        for (user, candidate) in smaller {
+            candidates.entry(user).or_default().merge(candidate);
+        }
+        SearchResult {
+            candidates,
+            edits_outside: self.edits_outside.merge(other.edits_outside),
+            tiles_placed: self.tiles_placed.merge(other.tiles_placed),
         }
     }
+}
+
+/**
+ * Find the edits users have in the search areas, last edit of every tile is tracked in the same pass
+ * when a tracker is given
+ */
+fn search_edits(settings: &Settings, tile_data: &TileData, last_writers: Option<&LastWriters>) -> SearchResult {
+    //Get list of potential users in selected areas, edits outside the areas are counted in the same pass
+    let locations = &settings.search_areas;
+    if settings.no_edits_outside {
+        info!("Finding users who have edits in selected areas and counting their edits outside them");
+        collect_user_edits(all_edits, locations, tile_data, last_writers)
+    } else {
+        info!("Finding users who have edits in selected areas");
+        collect_user_edits(internal_edits, locations, tile_data, last_writers)
+    }
+}
+
+/**
+ * Get the search areas the edit is inside of
+ */
+fn internal_edits(row_result: &CanvasLine, locations: &[SearchArea]) -> Option<CandidateEdit> {
+    //Check if search areas match the line
+    let matching: Vec<usize> = locations.iter().enumerate().filter(|(_, location)| {
+        location.contains(row_result)
+    }).map(|(index, _)| {
+        index
+    }).collect();
+    if matching.is_empty() {
+        None
+    } else {
+        Some(CandidateEdit::Inside(matching))
+    }
+}
+
+/**
+ * Get the search areas the edit is inside of, or if the edit is outside all search areas
+ */
+fn all_edits(row_result: &CanvasLine, locations: &[SearchArea]) -> Option<CandidateEdit> {
+    let is_outside = !locations.iter().any(|location| {
+        location.area.contains_edit(&row_result.coordinate)
+    });
     //Edit is not in any selected area
     if is_outside {
-        match users.lock() {
-            Ok(mut g) => {
-                g.remove(&row_result.user_id);
+        Some(CandidateEdit::Outside)
+    } else {
+        internal_edits(row_result, locations)
+    }
+}
+
+/**
+ * Function that calls the supplied function on the rows in parallel, each thread collects
+ * the returned edits to its own maps and counts which are merged at the end
+ */
+fn collect_user_edits<F>(edit_func: F, locations: &[SearchArea], tile_data: &TileData, last_writers: Option<&LastWriters>) -> SearchResult
+    where F: Fn(&CanvasLine, &[SearchArea]) -> Option<CandidateEdit> + Send + Sync {
+    tile_data.par_fold(|| {
+        SearchResult {
+            candidates: HashMap::new(),
+            edits_outside: UserCounts::default(),
+            tiles_placed: UserCounts::default(),
+        }
+    }, |mut result, index, row| {
+        match edit_func(row, locations) {
+            Some(CandidateEdit::Inside(areas)) => {
+                result.candidates.entry(row.user_id).or_default().apply(areas);
             }
-            Err(e) => {
-                eprintln!("Mutex lock failed: {}", e);
+            Some(CandidateEdit::Outside) => {
+                result.edits_outside.add(row.user_id);
             }
+            None => {}
         }
-    }
+        //Track the last edit of every tile in the same pass when requested
+        if let Some(last_writers) = last_writers {
+            if last_writers.apply(index, row) {
+                result.tiles_placed.add(row.user_id);
+            }
+        }
+        result
+    }, SearchResult::merge)
 }
 
 /**
- * Function that calls the supplied function on the rows of the text file in a thread
+ * Tiles of a user that survived
  */
-fn mutate_user_list<F: 'static>(update_func: F, locations: &Vec<SearchArea>, file_name: &str, users: Arc<Mutex<HashMap<String, HashSet<TileRegion>>>>, year: &PlaceDataSet)
-    where F: Fn(Arc<Mutex<HashMap<String, HashSet<TileRegion>>>>, &str, &Vec<SearchArea>, &PlaceDataSet) + Send + Sync + Copy {
-    let file = File::open(file_name)
-        .expect("Failed to open tile data");
-    let reader = BufReader::new(&file);
-
-    //Iterate over rows to find ALL users who placed tiles inside locations
-    let mut line_reader = reader.lines();
-    if line_reader.next().is_none() {
-        panic!("Could not skip CSV header");
-    };
+struct SurvivingTiles {
+    //Number of tiles user has placed
+    tiles_placed: u64,
+    //Tiles that made it to the start of whiteout with their colour
+    final_image: HashMap<TileLocation, [u8; 3]>,
+    //Tiles that made it to the end with the time they were placed
+    end: HashMap<TileLocation, PrimitiveDateTime>,
+}
 
-    line_reader.par_bridge().for_each(|line_result| {
-        match line_result {
-            Ok(l) => {
-                update_func(users.clone(), &l, locations, year);
-            }
-            Err(e) => {
-                warn!("Failed to obtain line from tile data: {}", e);
+/**
+ * Check if the edit is in the analysed time, and if it was made before the final image
+ */
+fn edit_period(timestamp: PrimitiveDateTime, final_image_time: Option<PrimitiveDateTime>, survival_time: Option<PrimitiveDateTime>) -> Option<bool> {
+    //Edits after the requested time did not exist yet
+    if survival_time.is_some_and(|t| timestamp > t) {
+        return None;
+    }
+    //Edits made during the whiteout are not on the final image
+    Some(final_image_time.is_none_or(|t| timestamp < t))
+}
+
+/**
+ * Get surviving tiles, rows are processed in parallel and the last edit of every tile of the canvas is tracked
+ */
+fn find_remaining_tiles(user: UserId, tile_data: &TileData, canvas: &TileRegion, final_image_time: Option<PrimitiveDateTime>,
+                        survival_time: Option<PrimitiveDateTime>) -> SurvivingTiles {
+    let last_writers = LastWriters::new(canvas.clone(), final_image_time, survival_time);
+    let (tiles_placed, mut found) = tile_data.par_fold(|| {
+        (0, Vec::new())
+    }, |(mut tiles_placed, mut found), position, row_result| {
+        let is_user = row_result.user_id == user;
+        if is_user {
+            if let LineCoordinate::Tile(t) = &row_result.coordinate {
+                found.push((position, row_result.timestamp, row_result.pixel_color, t.clone()));
             }
-        };
+        }
+        if last_writers.apply(position, row_result) && is_user {
+            tiles_placed += 1;
+        }
+        (tiles_placed, found)
+    }, |(tiles_placed, mut found), (other_tiles_placed, other_found)| {
+        found.extend(other_found);
+        (tiles_placed + other_tiles_placed, found)
     });
+
+    //Tiles of the user are logged in the order of the data
+    found.sort_by_key(|(position, ..)| *position);
+    for (_, timestamp, colour, t) in found {
+        info!("Found {} Color: {} tile placed at: {},{}", format_timestamp(&timestamp), format_colour(&colour), t.x, t.y);
+    }
+    last_writers.into_canvas().surviving_tiles(user, tiles_placed)
 }
 
 /**
- * Get surviving tiles
+ * Last edit of every tile of the canvas shared by the threads reading the rows. Rows can be applied
+ * in any order which lets the tiles of all users be tracked while searching for users
  */
-fn find_remaining_tiles(user_hash: &str, file_name: &str, year: &PlaceDataSet) {
-    let file = File::open(file_name).expect("Failed to open tile data");
-    let reader = BufReader::new(file);
-
-    const WHITEOUT_LINE_2023: usize = 126816301;
-    const WHITEOUT_LINE_2022: usize = 158117508;
-    let whiteout_line: usize = match year {
-        PlaceDataSet::Place2023 => { WHITEOUT_LINE_2023 }
-        PlaceDataSet::Place2022 => { WHITEOUT_LINE_2022 }
-    };
-    let mut reached_whiteout = false;
-    //Number of tiles user has placed
-    let mut tiles_placed: u64 = 0;
-    //Tiles that made it to the start of whiteout
-    let mut whiteout_tiles: HashMap<TileLocation, String> = HashMap::new();
-    //Tiles that made it to the end
-    let mut end_tiles: HashMap<TileLocation, String> = HashMap::new();
-
-    let mut line_reader = reader.lines().enumerate();
-    if line_reader.next().is_none() {
-        panic!("Could not skip CSV header");
-    };
-    for (line_number, line_result) in line_reader {
-        if line_number == whiteout_line {
-            reached_whiteout = true;
+struct LastWriters {
+    final_image_time: Option<PrimitiveDateTime>,
+    survival_time: Option<PrimitiveDateTime>,
+    //Canvas at the start of whiteout, without a final image time it is the same as the end
+    final_image: Option<SharedCanvasState>,
+    //Canvas at the survival time
+    end: SharedCanvasState,
+}
+
+impl LastWriters {
+    fn new(canvas: TileRegion, final_image_time: Option<PrimitiveDateTime>, survival_time: Option<PrimitiveDateTime>) -> LastWriters {
+        LastWriters {
+            final_image_time,
+            survival_time,
+            final_image: final_image_time.map(|_| SharedCanvasState::new(canvas.clone())),
+            end: SharedCanvasState::new(canvas),
         }
+    }
 
-        let line = match line_result {
-            Ok(l) => { l }
-            Err(e) => {
-                warn!("Failed to obtain line from tile data: {}", e);
-                continue;
-            }
+    /**
+     * Apply the row with the given position in the data, later rows replace the edits of earlier ones.
+     * Returns false if the edit was made after the survival time and is not counted as placed
+     */
+    fn apply(&self, position: u64, row: &CanvasLine) -> bool {
+        let before_final_image = match edit_period(row.timestamp, self.final_image_time, self.survival_time) {
+            Some(v) => { v }
+            None => { return false; }
         };
+        if before_final_image {
+            if let Some(final_image) = &self.final_image {
+                final_image.apply(position, row);
+            }
+        }
+        self.end.apply(position, row);
+        true
+    }
 
-        let row_result = match year {
-            PlaceDataSet::Place2023 => {
-                match CanvasLine::parse2023(&line) {
-                    Ok((_, v)) => { v }
-                    Err(_) => {
-                        warn!("Malformed line in data: {}", line);
-                        continue;
-                    }
-                }
+    /**
+     * Canvas at the final image and at the end once all rows have been applied
+     */
+    fn into_canvas(self) -> TrackedCanvas {
+        TrackedCanvas {
+            final_image: self.final_image.map(SharedCanvasState::into_state),
+            end: self.end.into_state(),
+        }
+    }
+}
+
+/**
+ * Canvas at the start of whiteout and at the survival time
+ */
+struct TrackedCanvas {
+    final_image: Option<CanvasState>,
+    end: CanvasState,
+}
+
+impl TrackedCanvas {
+    /**
+     * Canvas at the start of whiteout
+     */
+    fn final_image(&self) -> &CanvasState {
+        self.final_image.as_ref().unwrap_or(&self.end)
+    }
+
+    /**
+     * Tiles of the user that survived
+     */
+    fn surviving_tiles(&self, user: UserId, tiles_placed: u64) -> SurvivingTiles {
+        SurvivingTiles {
+            tiles_placed,
+            final_image: self.final_image().pixels_of(user).map(|(tile, pixel)| {
+                (tile, pixel.colour)
+            }).collect(),
+            end: self.end.pixels_of(user).map(|(tile, pixel)| {
+                (tile, pixel.time)
+            }).collect(),
+        }
+    }
+}
+
+/**
+ * Print the tiles that survived
+ */
+fn print_remaining_tiles(surviving: &SurvivingTiles, survival_time: Option<PrimitiveDateTime>) {
+    //Print the number of tiles user placed
+    println!("User placed  {} tiles total", surviving.tiles_placed);
+
+    //Print out all the tiles that made it to the start of whiteout
+    if surviving.final_image.is_empty() {
+        println!("No tiles on the final image");
+    } else {
+        println!("Following tiles made it to the final image:");
+    }
+
+    //Tiles are printed from top to bottom so the output is the same between runs
+    let mut final_image: Vec<(&TileLocation, &[u8; 3])> = surviving.final_image.iter().collect();
+    final_image.sort_by_key(|(location, _)| (location.y, location.x));
+    for (location, color) in final_image {
+        println!("{} at: {}", format_colour(color), location);
+    }
+
+
+    //Print out all the tiles that made it to  the end
+    match survival_time {
+        Some(t) => {
+            if surviving.end.is_empty() {
+                println!("No tiles survived to {}", format_timestamp(&t));
+            } else {
+                println!("Following tiles survived to {}:", format_timestamp(&t));
             }
-            PlaceDataSet::Place2022 => {
-                match CanvasLine::parse2022(&line) {
-                    Ok((_, v)) => { v }
-                    Err(_) => {
-                        warn!("Malformed line in data: {}", line);
-                        continue;
-                    }
-                }
+        }
+        None => {
+            if surviving.end.is_empty() {
+                println!("No tiles survived to the end");
+            } else {
+                println!("Following tiles made it to the end:")
             }
-        };
+        }
+    }
 
-        //Check that user is one who we want
-        if row_result.user_id == user_hash {
-            //Current user, add to tiles
-            tiles_placed += 1;
-            //Check if the tile is a region
-            match row_result.coordinate {
-                LineCoordinate::Tile(t) => {
-                    info!("Found {} Color: {} tile placed at: {},{}", row_result.timestamp, row_result.pixel_color, t.x, t.y);
-                    //Add tiles that could have survived to the whiteout
-                    if !reached_whiteout {
-                        whiteout_tiles.insert(t.clone(), row_result.pixel_color.clone());
+    let mut end: Vec<(&TileLocation, &PrimitiveDateTime)> = surviving.end.iter().collect();
+    end.sort_by_key(|(location, _)| (location.y, location.x));
+    for (location, time) in end {
+        println!("{} placed at {}", location, format_timestamp(time));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::panic::AssertUnwindSafe;
+    use crate::input::{TempFile, write_csv, write_gz};
+    use super::*;
+
+    fn tile(x: i16, y: i16) -> TileLocation {
+        TileLocation {
+            x,
+            y,
+        }
+    }
+
+    /**
+     * Surviving tiles found by replaying the rows in the order of the data, tile of the user is removed
+     * when another user edits it. Kept separate from the tracking used by the tool so it can be checked against
+     */
+    fn reference_survival(content: &str, year: &PlaceDataSet, user: &str, final_image_time: Option<PrimitiveDateTime>,
+                          survival_time: Option<PrimitiveDateTime>) -> SurvivingTiles {
+        let users = UserTable::new();
+        let mut surviving = SurvivingTiles {
+            tiles_placed: 0,
+            final_image: HashMap::new(),
+            end: HashMap::new(),
+        };
+        for line in content.lines().skip(1) {
+            let (_, row) = year.parse_line(line, &users).expect("Valid test row");
+            if survival_time.is_some_and(|t| row.timestamp > t) {
+                continue;
+            }
+            let before_final_image = final_image_time.is_none_or(|t| row.timestamp < t);
+            let is_user = *users.name(row.user_id) == *user;
+            if is_user {
+                surviving.tiles_placed += 1;
+            }
+            row.coordinate.for_each_point(|tile| {
+                if is_user {
+                    if before_final_image {
+                        surviving.final_image.insert(tile.clone(), row.pixel_color);
                     }
-                    //Add tiles that could have survived to the end
-                    end_tiles.insert(t, row_result.timestamp.clone());
-                }
-                LineCoordinate::Region(r) => {
-                    for i in r.left..r.right {
-                        for j in r.top..r.bottom {
-                            let tile_location = TileLocation {
-                                x: i,
-                                y: j,
-                            };
-                            //Remove tiles that did not survive to the whiteout
-                            if !reached_whiteout {
-                                whiteout_tiles.insert(tile_location.clone(), row_result.pixel_color.clone());
-                            }
-                            //Remove tiles that did not survive to the end
-                            end_tiles.insert(tile_location, row_result.timestamp.clone());
-                        }
+                    surviving.end.insert(tile, row.timestamp);
+                } else {
+                    if before_final_image {
+                        surviving.final_image.remove(&tile);
                     }
+                    surviving.end.remove(&tile);
+                }
+            });
+        }
+        surviving
+    }
+
+    fn assert_same_tiles(actual: &SurvivingTiles, expected: &SurvivingTiles, context: &str) {
+        assert_eq!(actual.tiles_placed, expected.tiles_placed, "{}", context);
+        assert_eq!(actual.final_image, expected.final_image, "{}", context);
+        assert_eq!(actual.end, expected.end, "{}", context);
+    }
+
+    /**
+     * Find the surviving tiles of the user from the CSV with 2022-04-01 13:00:05 as the final image time,
+     * the tiles are checked against the reference
+     */
+    fn surviving_tiles(name: &str, year: PlaceDataSet, content: &str, user: &str) -> SurvivingTiles {
+        let csv = write_csv(name, content);
+        let final_image_time = Some(time::macros::datetime!(2022-04-01 13:00:05));
+        let expected = reference_survival(content, &year, user, final_image_time, None);
+        let tile_data = TileData::from_csv(vec![csv.path.clone()], year.clone());
+        let user = tile_data.users().intern(user);
+        let surviving = find_remaining_tiles(user, &tile_data, &year.canvas(), final_image_time, None);
+        assert_same_tiles(&surviving, &expected, name);
+        surviving
+    }
+
+    #[test]
+    fn circle_points_match_contains_point() {
+        let centres = [(0, 0), (5, 7), (-3, 12), (-1500, -1000), (1499, 999), (i16::MAX - 2, i16::MIN + 2)];
+        for (x, y) in centres {
+            for r in -1..=40 {
+                let circle = TileCircle {
+                    x,
+                    y,
+                    r,
+                };
+                let points: Vec<TileLocation> = circle.points().collect();
+                let unique: HashSet<&TileLocation> = points.iter().collect();
+                assert_eq!(unique.len(), points.len(), "Duplicate points for {:?}", circle);
+                for p in &points {
+                    assert!(circle.contains_point(p.x, p.y), "{:?} is not in {:?}", p, circle);
                 }
-                LineCoordinate::Circle(c) => {
-                    let points = c.points();
-                    for p in points {
-                        //Remove tiles that did not survive to the whiteout
-                        if !reached_whiteout {
-                            whiteout_tiles.insert(p.clone(), row_result.pixel_color.clone());
+
+                //Every tile accepted by contains_point around the circle is in points
+                let mut expected = 0;
+                for i in (x as i32 - 42)..=(x as i32 + 42) {
+                    for j in (y as i32 - 42)..=(y as i32 + 42) {
+                        let (Ok(i), Ok(j)) = (i16::try_from(i), i16::try_from(j)) else {
+                            continue;
+                        };
+                        if circle.contains_point(i, j) {
+                            expected += 1;
+                            assert!(unique.contains(&tile(i, j)), "{:?} is missing from {:?}", tile(i, j), circle);
                         }
-                        //Remove tiles that did not survive to the end
-                        end_tiles.insert(p, row_result.timestamp.clone());
                     }
                 }
+                assert_eq!(expected, points.len());
             }
-        } else {
-            //Was not current user, remove from tiles if present
-            match row_result.coordinate {
-                LineCoordinate::Tile(t) => {
-                    if !reached_whiteout {
-                        whiteout_tiles.remove(&t);
-                    }
-                    end_tiles.remove(&t);
-                }
-                LineCoordinate::Region(r) => {
-                    for i in r.left..r.right {
-                        for j in r.top..r.bottom {
-                            whiteout_tiles.remove(&TileLocation {
-                                x: i,
-                                y: j,
-                            });
-                        }
+        }
+    }
+
+    #[test]
+    fn circle_points_of_small_radius() {
+        let circle = |r| TileCircle {
+            x: 10,
+            y: 20,
+            r,
+        };
+        assert_eq!(circle(0).points().count(), 0);
+        assert_eq!(circle(1).points().collect::<Vec<_>>(), vec![tile(10, 20)]);
+        //Distance of the diagonal neighbours is sqrt(2) which is less than 2
+        assert_eq!(circle(2).points().count(), 9);
+        assert_eq!(circle(3).points().count(), 25);
+    }
+
+    fn region(left: i16, top: i16, right: i16, bottom: i16) -> TileRegion {
+        TileRegion {
+            top,
+            left,
+            bottom,
+            right,
+        }
+    }
+
+    #[test]
+    fn region_intersections() {
+        let base = region(10, 10, 20, 20);
+        let cases = [
+            (region(0, 0, 5, 5), false, "above left"),
+            (region(0, 12, 9, 18), false, "left"),
+            (region(21, 12, 30, 18), false, "right"),
+            (region(12, 0, 18, 9), false, "above"),
+            (region(12, 21, 18, 30), false, "below"),
+            (region(21, 21, 30, 30), false, "below right"),
+            (region(0, 0, 10, 10), true, "shares a corner tile"),
+            (region(20, 12, 30, 18), true, "shares the right edge"),
+            (region(5, 5, 12, 12), true, "overlaps a corner"),
+            (region(12, 5, 18, 12), true, "overlaps the top edge"),
+            (region(12, 12, 18, 18), true, "inside"),
+            (region(0, 0, 30, 30), true, "contains"),
+            (region(14, 0, 16, 30), true, "crosses vertically"),
+            (region(0, 14, 30, 16), true, "crosses horizontally"),
+            (region(10, 10, 20, 20), true, "identical"),
+            (region(15, 15, 15, 15), true, "single tile"),
+        ];
+        for (other, expected, name) in cases {
+            assert_eq!(base.intersects(&other), expected, "{}", name);
+            assert_eq!(other.intersects(&base), expected, "{} reversed", name);
+        }
+        //Cross shaped overlap where no corner is inside the other region
+        assert!(region(14, 0, 16, 30).intersects(&region(0, 14, 30, 16)));
+    }
+
+    #[test]
+    fn circle_intersections() {
+        let base = region(10, 10, 20, 20);
+        let circle = |x, y, r| TileCircle {
+            x,
+            y,
+            r,
+        };
+        let cases = [
+            (circle(15, 15, 2), true, "inside"),
+            (circle(15, 15, 50), true, "contains"),
+            (circle(15, 8, 3), true, "cuts the top edge"),
+            (circle(15, 7, 3), false, "touches the top edge at the radius"),
+            (circle(23, 15, 4), true, "cuts the right edge"),
+            (circle(5, 15, 6), true, "cuts the left edge"),
+            (circle(15, 25, 5), false, "below"),
+            (circle(8, 8, 3), true, "overlaps a corner"),
+            (circle(7, 7, 4), false, "near a corner"),
+            (circle(7, 7, 5), true, "reaches a corner"),
+            (circle(10, 10, 1), true, "single tile at the corner"),
+            (circle(15, 15, 0), false, "zero radius"),
+        ];
+        for (c, expected, name) in cases {
+            assert_eq!(c.intersects(&base), expected, "{}", name);
+            //Intersection agrees with the tiles of the circle
+            assert_eq!(c.points().any(|p| base.contains(&p)), expected, "{} points", name);
+        }
+    }
+
+    #[test]
+    fn tile_overwritten_by_other_user() {
+        let surviving = surviving_tiles("tile", PlaceDataSet::Place2022, "\
+timestamp,user_id,pixel_color,coordinate
+2022-04-01 13:00:00.000 UTC,A==,#FF4500,\"1,1\"
+2022-04-01 13:00:00.000 UTC,A==,#FF4500,\"2,2\"
+2022-04-01 13:00:01.000 UTC,B==,#000000,\"1,1\"
+", "A==");
+        assert_eq!(surviving.tiles_placed, 2);
+        assert_eq!(surviving.final_image.keys().collect::<Vec<_>>(), vec![&tile(2, 2)]);
+        assert_eq!(surviving.end.keys().collect::<Vec<_>>(), vec![&tile(2, 2)]);
+    }
+
+    #[test]
+    fn tile_overwritten_by_region_of_other_user() {
+        let surviving = surviving_tiles("region", PlaceDataSet::Place2022, "\
+timestamp,user_id,pixel_color,coordinate
+2022-04-01 13:00:00.000 UTC,A==,#FF4500,\"10,10\"
+2022-04-01 13:00:00.000 UTC,A==,#FF4500,\"12,12\"
+2022-04-01 13:00:00.000 UTC,A==,#FF4500,\"13,10\"
+2022-04-01 13:00:01.000 UTC,M==,#FFFFFF,\"10,10,12,12\"
+", "A==");
+        //Edges of the region are cleared
+        assert_eq!(surviving.final_image.keys().collect::<Vec<_>>(), vec![&tile(13, 10)]);
+        assert_eq!(surviving.end.keys().collect::<Vec<_>>(), vec![&tile(13, 10)]);
+    }
+
+    #[test]
+    fn tile_overwritten_by_circle_of_other_user() {
+        let surviving = surviving_tiles("circle", PlaceDataSet::Place2023, "\
+timestamp,user,coordinate,pixel_color
+2022-04-01 13:00:00.000 UTC,A==,\"5,5\",#FF4500
+2022-04-01 13:00:00.000 UTC,A==,\"10,5\",#FF4500
+2022-04-01 13:00:01.000 UTC,M==,\"{X: 5, Y: 5, R: 2}\",#FFFFFF
+", "A==");
+        assert_eq!(surviving.final_image.keys().collect::<Vec<_>>(), vec![&tile(10, 5)]);
+        assert_eq!(surviving.end.keys().collect::<Vec<_>>(), vec![&tile(10, 5)]);
+    }
+
+    #[test]
+    fn region_of_user_includes_edges() {
+        let surviving = surviving_tiles("own_region", PlaceDataSet::Place2022, "\
+timestamp,user_id,pixel_color,coordinate
+2022-04-01 13:00:00.000 UTC,A==,#FFFFFF,\"0,0,1,1\"
+2022-04-01 13:00:01.000 UTC,B==,#000000,\"1,1\"
+", "A==");
+        let mut end: Vec<&TileLocation> = surviving.end.keys().collect();
+        end.sort_by_key(|t| (t.x, t.y));
+        assert_eq!(end, vec![&tile(0, 0), &tile(0, 1), &tile(1, 0)]);
+        assert_eq!(surviving.final_image.len(), 3);
+    }
+
+    #[test]
+    fn whiteout_only_affects_end() {
+        let surviving = surviving_tiles("whiteout", PlaceDataSet::Place2022, "\
+timestamp,user_id,pixel_color,coordinate
+2022-04-01 13:00:00.000 UTC,A==,#FF4500,\"1,1\"
+2022-04-01 13:00:00.000 UTC,A==,#FF4500,\"2,2\"
+2022-04-01 13:00:06.000 UTC,B==,#FFFFFF,\"1,1,2,1\"
+2022-04-01 13:00:07.000 UTC,A==,#000000,\"3,3\"
+", "A==");
+        let mut final_image: Vec<&TileLocation> = surviving.final_image.keys().collect();
+        final_image.sort_by_key(|t| (t.x, t.y));
+        assert_eq!(final_image, vec![&tile(1, 1), &tile(2, 2)]);
+        let mut end: Vec<&TileLocation> = surviving.end.keys().collect();
+        end.sort_by_key(|t| (t.x, t.y));
+        assert_eq!(end, vec![&tile(2, 2), &tile(3, 3)]);
+    }
+
+    #[test]
+    fn later_edit_of_user_replaces_colour() {
+        let surviving = surviving_tiles("own_overwrite", PlaceDataSet::Place2022, "\
+timestamp,user_id,pixel_color,coordinate
+2022-04-01 13:00:00.000 UTC,A==,#FF4500,\"1,1\"
+2022-04-01 13:00:01.000 UTC,B==,#000000,\"1,1\"
+2022-04-01 13:00:02.000 UTC,A==,#FFFFFF,\"1,1\"
+", "A==");
+        assert_eq!(surviving.tiles_placed, 2);
+        assert_eq!(surviving.final_image.get(&tile(1, 1)), Some(&[255, 255, 255]));
+        assert_eq!(surviving.end.get(&tile(1, 1)), Some(&time::macros::datetime!(2022-04-01 13:00:02)));
+    }
+
+    #[test]
+    fn ties_are_ranked_by_hashed_id() {
+        let users = UserTable::new();
+        let candidate = |edits_inside| Candidate {
+            areas: HashSet::new(),
+            edits_inside,
+            edits_outside: 0,
+        };
+        //Ids are given in the opposite order of the names
+        let candidates = HashMap::from([
+            (users.intern("C=="), candidate(2)),
+            (users.intern("B=="), candidate(3)),
+            (users.intern("A=="), candidate(2)),
+        ]);
+        let ranked: Vec<String> = rank_candidates(candidates, &[], 1.0, &users).iter()
+            .map(|r| users.name(r.user).to_string())
+            .collect();
+        assert_eq!(ranked, vec!["B==", "A==", "C=="]);
+    }
+
+    #[test]
+    fn outside_edits_are_counted_for_candidates() {
+        let csv = write_csv("outside_edits", "\
+timestamp,user_id,pixel_color,coordinate
+2022-04-01 13:00:00.000 UTC,A==,#FF4500,\"1,1\"
+2022-04-01 13:00:01.000 UTC,B==,#FF4500,\"20,20\"
+2022-04-01 13:00:02.000 UTC,A==,#FF4500,\"20,1\"
+2022-04-01 13:00:03.000 UTC,C==,#FF4500,\"2,2\"
+2022-04-01 13:00:04.000 UTC,A==,#FF4500,\"5,5,30,30\"
+2022-04-01 13:00:05.000 UTC,B==,#FF4500,\"21,20\"
+2022-04-01 13:00:06.000 UTC,A==,#FF4500,\"21,1\"
+2022-04-01 13:00:07.000 UTC,A==,#FF4500,\"22,1\"
+");
+        let locations = [SearchArea {
+            start_time: None,
+            end_time: None,
+            is_optional: false,
+            colours: Vec::new(),
+            area: AreaShape::Region(TileRegion {
+                left: 0,
+                top: 0,
+                right: 9,
+                bottom: 9,
+            }),
+        }];
+        //Rows are split between threads so the counts of the threads are merged
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(4).build().expect("Failed to create thread pool");
+        let tile_data = TileData::from_csv(vec![csv.path.clone()], PlaceDataSet::Place2022).with_chunk_sizes(1, 40);
+        let edits = |edit_func: fn(&CanvasLine, &[SearchArea]) -> Option<CandidateEdit>| {
+            let mut candidates: Vec<(String, u64, u64)> = pool.install(|| collect_user_edits(edit_func, &locations, &tile_data, None))
+                .candidates().into_iter()
+                .map(|(user, c)| (tile_data.users().name(user).to_string(), c.edits_inside, c.edits_outside))
+                .collect();
+            candidates.sort();
+            candidates
+        };
+        //Users with only outside edits are not candidates
+        assert_eq!(edits(all_edits), vec![("A==".to_string(), 2, 3), ("C==".to_string(), 1, 0)]);
+        assert_eq!(edits(internal_edits), vec![("A==".to_string(), 2, 0), ("C==".to_string(), 1, 0)]);
+    }
+
+    #[test]
+    fn parse_2017_rows() {
+        let users = UserTable::new();
+        let (_, row) = PlaceDataSet::Place2017.parse_line("1490918688000,A==,162,147,2", &users)
+            .expect("Valid row with milliseconds");
+        assert_eq!(row.timestamp, time::macros::datetime!(2017-03-31 00:04:48));
+        assert_eq!(row.user_id, users.intern("A=="));
+        assert_eq!(row.coordinate, LineCoordinate::Tile(tile(162, 147)));
+        assert_eq!(format_colour(&row.pixel_color), "#888888");
+
+        let (_, row) = PlaceDataSet::Place2017.parse_line("2017-04-01 12:00:00.5 UTC,B==,0,999,15", &users)
+            .expect("Valid row with formatted time");
+        assert_eq!(row.timestamp, time::macros::datetime!(2017-04-01 12:00:00.5));
+        assert_eq!(row.coordinate, LineCoordinate::Tile(tile(0, 999)));
+        assert_eq!(format_colour(&row.pixel_color), "#820080");
+
+        let (_, row) = PlaceDataSet::Place2017.parse_line("1490918688000,A==,1,1,0", &users)
+            .expect("Valid row with first colour");
+        assert_eq!(format_colour(&row.pixel_color), "#FFFFFF");
+
+        //Palette has 16 colours
+        assert!(PlaceDataSet::Place2017.parse_line("1490918688000,A==,1,1,16", &users).is_err());
+    }
+
+    #[test]
+    fn detect_data_set_of_files() {
+        let files = [
+            ("detect_2022", "timestamp,user_id,pixel_color,coordinate\n2022-04-01 13:00:00.000 UTC,A==,#FF4500,\"1,1\"\n", PlaceDataSet::Place2022),
+            ("detect_2023", "timestamp,user,coordinate,pixel_color\n2023-07-20 13:00:00.000 UTC,A==,\"1,1\",#FF4500\n", PlaceDataSet::Place2023),
+            ("detect_2017", "ts,user_hash,x_coordinate,y_coordinate,color\n1490918688000,A==,1,1,2\n", PlaceDataSet::Place2017),
+            //Unknown header, data set is detected from the first row
+            ("detect_row_2022", "time,user,colour,tile\n2022-04-01 13:00:00.000 UTC,A==,#FF4500,\"1,1\"\n", PlaceDataSet::Place2022),
+            ("detect_row_2023", "time,user,tile,colour\n2023-07-20 13:00:00.000 UTC,A==,\"{X: 1, Y: 1, R: 2}\",#FF4500\n", PlaceDataSet::Place2023),
+            ("detect_row_2017", "time,user,x,y,colour\n1490918688000,A==,1,1,2\n", PlaceDataSet::Place2017),
+        ];
+        for (name, content, year) in files {
+            let csv = write_csv(name, content);
+            let detected = detect_year(std::slice::from_ref(&csv.path), None);
+            let configured = detect_year(std::slice::from_ref(&csv.path), Some(&year));
+            assert_eq!(detected, year, "{}", name);
+            assert_eq!(configured, year, "{}", name);
+        }
+
+        //Configured year is used when the file is not recognized
+        let csv = write_csv("detect_unknown", "a,b\n1,2\n");
+        let configured = detect_year(std::slice::from_ref(&csv.path), Some(&PlaceDataSet::Place2023));
+        assert_eq!(configured, PlaceDataSet::Place2023);
+    }
+
+    /**
+     * Check every way of finding the surviving tiles against the reference for all users of the data.
+     * The data is read with small chunks on several threads so rows are applied out of order and
+     * accumulators built from different parts of the data are merged, both from the memory mapped
+     * and the compressed file
+     */
+    fn assert_survival_matches_reference(name: &str, content: &str, users: &[&str], times: &[(Option<PrimitiveDateTime>, Option<PrimitiveDateTime>)]) {
+        let year = PlaceDataSet::Place2022;
+        //Test data only edits the corner of the canvas
+        let canvas = TileRegion {
+            left: 0,
+            top: 0,
+            right: 63,
+            bottom: 63,
+        };
+        let csv = write_csv(name, content);
+        let compressed = write_gz(name, content);
+
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(4).build().expect("Failed to create thread pool");
+        pool.install(|| {
+            let sources = [
+                TileData::from_csv(vec![csv.path.clone()], year.clone()),
+                TileData::from_csv(vec![csv.path.clone()], year.clone()).with_chunk_sizes(7, 300),
+                TileData::from_csv(vec![compressed.path.clone()], year.clone()).with_chunk_sizes(7, 300),
+            ];
+            for (tile_data, (final_image_time, survival_time)) in sources.iter().flat_map(|t| times.iter().map(move |times| (t, *times))) {
+                //Tracking done while searching for users with single_scan
+                let last_writers = LastWriters::new(canvas.clone(), final_image_time, survival_time);
+                let search = collect_user_edits(all_edits, &[], tile_data, Some(&last_writers));
+                let single_scan = last_writers.into_canvas();
+
+                //Rows applied in reverse order
+                let last_writers = LastWriters::new(canvas.clone(), final_image_time, survival_time);
+                let mut tiles_placed = UserCounts::default();
+                let lines: Vec<&str> = content.lines().skip(1).collect();
+                for (position, line) in lines.iter().enumerate().rev() {
+                    let (_, row) = year.parse_line(line, tile_data.users()).expect("Valid test row");
+                    if last_writers.apply(position as u64, &row) {
+                        tiles_placed.add(row.user_id);
                     }
                 }
-                LineCoordinate::Circle(c) => {
-                    let points = c.points();
-                    for p in points {
-                        whiteout_tiles.remove(&p);
-                    }
+                let reversed = last_writers.into_canvas();
+
+                for name in users {
+                    let expected = reference_survival(content, &year, name, final_image_time, survival_time);
+                    let user = tile_data.users().intern(name);
+                    let context = format!("{} with {:?} and {:?}", name, final_image_time, survival_time);
+                    assert_same_tiles(&find_remaining_tiles(user, tile_data, &canvas, final_image_time, survival_time), &expected, &context);
+                    assert_same_tiles(&single_scan.surviving_tiles(user, search.tiles_placed.get(user)), &expected, &context);
+                    assert_same_tiles(&reversed.surviving_tiles(user, tiles_placed.get(user)), &expected, &context);
                 }
             }
-        }
+        });
     }
 
-    //Print the number of tiles user placed
-    println!("User placed  {} tiles total", tiles_placed);
+    #[test]
+    fn last_writers_match_reference() {
+        //Edits at the same time are ordered by their row in the data
+        let content = "\
+timestamp,user_id,pixel_color,coordinate
+2022-04-01 13:00:00.000 UTC,A==,#FF4500,\"1,1\"
+2022-04-01 13:00:00.000 UTC,B==,#000000,\"1,1\"
+2022-04-01 13:00:00.000 UTC,A==,#FFFFFF,\"2,2\"
+2022-04-01 13:00:01.000 UTC,B==,#000000,\"0,0,2,1\"
+2022-04-01 13:00:02.000 UTC,A==,#FF4500,\"2,1\"
+2022-04-01 13:00:03.000 UTC,C==,#FFFFFF,\"1,2,2,2\"
+2022-04-01 13:00:06.000 UTC,B==,#FFFFFF,\"2,1\"
+2022-04-01 13:00:07.000 UTC,C==,#000000,\"5,5\"
+";
+        assert_survival_matches_reference("last_writers", content, &["A==", "B==", "C=="], &[
+            (None, None),
+            (Some(time::macros::datetime!(2022-04-01 13:00:05)), None),
+            (Some(time::macros::datetime!(2022-04-01 13:00:02)), Some(time::macros::datetime!(2022-04-01 13:00:06))),
+        ]);
+    }
 
-    //Print out all the tiles that made it to the start of whiteout
-    if whiteout_tiles.is_empty() {
-        println!("No tiles on the final image");
-    } else {
-        println!("Following tiles made it to the final image:");
+    #[test]
+    fn survival_matches_reference() {
+        //Random edits on a small canvas so tiles are overwritten often, many edits share a timestamp
+        let mut seed: u64 = 12345;
+        let mut next = |limit: u64| {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (seed >> 33) % limit
+        };
+        let mut content = String::from("timestamp,user_id,pixel_color,coordinate\n");
+        for i in 0..5000 {
+            let user = next(6);
+            let colour = ["#FFFFFF", "#000000", "#FF4500"][next(3) as usize];
+            let (x, y) = (next(30), next(30));
+            let coordinate = if next(50) == 0 {
+                format!("{},{},{},{}", x, y, x + next(5), y + next(5))
+            } else {
+                format!("{},{}", x, y)
+            };
+            content.push_str(&format!("2022-04-01 13:{:02}:{:02}.000 UTC,U{}==,{},\"{}\"\n",
+                                      i / 1000, (i / 20) % 60, user, colour, coordinate));
+        }
+        assert_survival_matches_reference("survival", &content, &["U0==", "U1==", "U2==", "U3==", "U4==", "U5=="], &[
+            (None, None),
+            (Some(time::macros::datetime!(2022-04-01 13:03:00)), None),
+            (Some(time::macros::datetime!(2022-04-01 13:02:00)), Some(time::macros::datetime!(2022-04-01 13:04:10))),
+        ]);
+    }
+
+    #[test]
+    fn overlapping_files_are_rejected() {
+        let first_content = "\
+timestamp,user_id,pixel_color,coordinate
+2022-04-01 13:00:00.000 UTC,A==,#FF4500,\"1,1\"
+2022-04-01 13:00:01.000 UTC,A==,#FF4500,\"1,2\"
+2022-04-01 13:00:02.000 UTC,A==,#FF4500,\"1,3\"
+2022-04-01 13:00:03.000 UTC,A==,#FF4500,\"1,4\"
+2022-04-01 13:00:05.000 UTC,A==,#FF4500,\"1,1\"
+";
+        let first = write_csv("order_first", first_content);
+        let first_compressed = write_gz("order_first", first_content);
+        let second = write_csv("order_second", "\
+timestamp,user_id,pixel_color,coordinate
+2022-04-01 13:00:05.000 UTC,B==,#FF4500,\"1,1\"
+2022-04-01 13:00:09.000 UTC,B==,#FF4500,\"1,1\"
+");
+        let overlapping = write_csv("order_overlapping", "\
+timestamp,user_id,pixel_color,coordinate
+2022-04-01 13:00:04.000 UTC,B==,#FF4500,\"1,1\"
+2022-04-01 13:00:10.000 UTC,B==,#FF4500,\"1,1\"
+");
+        //Files are checked while they are read, rows of the first file are split between threads
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(4).build().expect("Failed to create thread pool");
+        let read = |first: &TempFile, second: &TempFile| {
+            let files = vec![first.path.clone(), second.path.clone()];
+            let tile_data = TileData::from_csv(files, PlaceDataSet::Place2022).with_chunk_sizes(1, 40);
+            let folded = std::panic::catch_unwind(AssertUnwindSafe(|| {
+                pool.install(|| tile_data.par_fold(|| 0, |count, _, _| count + 1, |a, b| a + b))
+            }));
+            let mut rows = 0;
+            let replayed = std::panic::catch_unwind(AssertUnwindSafe(|| tile_data.for_each(|_| rows += 1)));
+            (folded.ok(), replayed.ok().map(|_| rows))
+        };
+        assert_eq!(read(&first, &second), (Some(7), Some(7)));
+        assert_eq!(read(&first_compressed, &second), (Some(7), Some(7)));
+        assert_eq!(read(&first, &overlapping), (None, None));
+        assert_eq!(read(&first_compressed, &overlapping), (None, None));
     }
 
-    for (location, color) in &whiteout_tiles {
-        println!("{} at: {}", color, location);
+    fn ranked_users(count: usize) -> (UserTable, Vec<RankedUser>) {
+        let users = UserTable::new();
+        let ranked = (0..count).map(|i| RankedUser {
+            user: users.intern(&format!("user{}", i)),
+            score: 1.0,
+            candidate: Candidate::default(),
+        }).collect();
+        (users, ranked)
     }
 
+    #[test]
+    fn selection_modes() {
+        let (users, ranked) = ranked_users(3);
+        let all: Vec<UserId> = ranked.iter().map(|r| r.user).collect();
+        //Modes other than interactive do not read input
+        let mut input = io::Cursor::new("1\n");
+        assert_eq!(select_users(&ranked, &Selection::All, &mut input, false), all);
+        assert_eq!(select_users(&ranked, &Selection::Best, &mut input, false), vec![users.intern("user0")]);
+        assert!(select_users(&ranked, &Selection::List, &mut input, false).is_empty());
+        assert_eq!(input.position(), 0);
 
-    //Print out all the tiles that made it to  the end
-    if end_tiles.is_empty() {
-        println!("No tiles survived to the end");
-    } else {
-        println!("Following tiles made it to the end:")
+        assert_eq!(select_users(&ranked, &Selection::Interactive, &mut input, false), vec![users.intern("user1")]);
+        //Only user is selected without asking
+        assert_eq!(select_users(&ranked[2..], &Selection::Interactive, &mut io::Cursor::new(""), false), vec![all[2]]);
     }
 
-    for (location, time) in &end_tiles {
-        println!("{} placed at {}", location, time);
+    #[test]
+    fn interactive_selection_without_terminal_reads_once() {
+        let (_, ranked) = ranked_users(3);
+        //Input ended
+        assert!(select_users(&ranked, &Selection::Interactive, &mut io::Cursor::new(""), false).is_empty());
+        //Invalid index is not asked again when input is piped
+        let mut input = io::Cursor::new("3\n1\n");
+        assert!(select_users(&ranked, &Selection::Interactive, &mut input, false).is_empty());
+        assert_eq!(input.position(), 2);
+        let mut input = io::Cursor::new("first\n1\n");
+        assert!(select_users(&ranked, &Selection::Interactive, &mut input, false).is_empty());
+        //On a terminal invalid input is asked again
+        let mut input = io::Cursor::new("3\nfirst\n2\n");
+        assert_eq!(select_users(&ranked, &Selection::Interactive, &mut input, true), vec![ranked[2].user]);
     }
 }
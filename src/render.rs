@@ -0,0 +1,772 @@
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::BufWriter;
+use log::{info, warn};
+use serde::Deserialize;
+use time::{Duration, PrimitiveDateTime};
+use crate::{format_timestamp, rplace_time_format, SearchArea, TileRegion};
+use crate::canvas::CanvasState;
+use crate::input::TileData;
+use crate::users::UserId;
+
+/**
+ * Colour of tiles nobody has edited, the canvas starts white
+ */
+pub(crate) const BACKGROUND: [u8; 3] = [255, 255, 255];
+
+/**
+ * RGB image with 8 bits per channel
+ */
+pub(crate) struct RgbImage {
+    width: u32,
+    height: u32,
+    pixels: Vec<u8>,
+}
+
+impl RgbImage {
+    pub(crate) fn new(width: u32, height: u32, colour: [u8; 3]) -> RgbImage {
+        RgbImage {
+            width,
+            height,
+            pixels: colour.repeat(width as usize * height as usize),
+        }
+    }
+
+    pub(crate) fn set(&mut self, x: u32, y: u32, colour: [u8; 3]) {
+        let i = (y as usize * self.width as usize + x as usize) * 3;
+        self.pixels[i..i + 3].copy_from_slice(&colour);
+    }
+
+    /**
+     * Enlarge the image so every pixel becomes a square of scale × scale pixels
+     */
+    pub(crate) fn scaled(&self, scale: u32) -> RgbImage {
+        if scale <= 1 {
+            return RgbImage {
+                width: self.width,
+                height: self.height,
+                pixels: self.pixels.clone(),
+            };
+        }
+        let mut scaled = RgbImage::new(self.width * scale, self.height * scale, BACKGROUND);
+        for y in 0..scaled.height {
+            for x in 0..scaled.width {
+                let i = ((y / scale) as usize * self.width as usize + (x / scale) as usize) * 3;
+                scaled.set(x, y, [self.pixels[i], self.pixels[i + 1], self.pixels[i + 2]]);
+            }
+        }
+        scaled
+    }
+
+    /**
+     * Write the image as PNG
+     */
+    pub(crate) fn save(&self, path: &str) -> Result<(), String> {
+        let file = File::create(path)
+            .map_err(|e| format!("Failed to create image {}: {}", path, e))?;
+        let mut encoder = png::Encoder::new(BufWriter::new(file), self.width, self.height);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header()
+            .map_err(|e| format!("Failed to write image {}: {}", path, e))?;
+        writer.write_image_data(&self.pixels)
+            .map_err(|e| format!("Failed to write image {}: {}", path, e))
+    }
+}
+
+/**
+ * Animated PNG that is written a frame at a time, the number of frames is set when it is created
+ */
+pub(crate) struct AnimationWriter {
+    path: String,
+    writer: png::Writer<BufWriter<File>>,
+}
+
+impl AnimationWriter {
+    pub(crate) fn create(path: &str, width: u32, height: u32, frames: u32, frame_delay_ms: u16) -> Result<AnimationWriter, String> {
+        let file = File::create(path)
+            .map_err(|e| format!("Failed to create image {}: {}", path, e))?;
+        let mut encoder = png::Encoder::new(BufWriter::new(file), width, height);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.set_animated(frames, 0)
+            .and_then(|_| encoder.set_frame_delay(frame_delay_ms, 1000))
+            .map_err(|e| format!("Failed to write image {}: {}", path, e))?;
+        let writer = encoder.write_header()
+            .map_err(|e| format!("Failed to write image {}: {}", path, e))?;
+        Ok(AnimationWriter {
+            path: path.to_string(),
+            writer,
+        })
+    }
+
+    /**
+     * Write the next frame, frames need to have the size of the animation
+     */
+    pub(crate) fn add(&mut self, frame: &RgbImage) -> Result<(), String> {
+        self.writer.write_image_data(&frame.pixels)
+            .map_err(|e| format!("Failed to write image {}: {}", self.path, e))
+    }
+
+    /**
+     * Finish the file after all the frames have been written
+     */
+    pub(crate) fn finish(self) -> Result<(), String> {
+        self.writer.finish()
+            .map_err(|e| format!("Failed to write image {}: {}", self.path, e))
+    }
+}
+
+/**
+ * Size of the region in tiles
+ */
+pub(crate) fn region_size(region: &TileRegion) -> (u32, u32) {
+    let width = (region.right as i32 - region.left as i32 + 1).max(0) as u32;
+    let height = (region.bottom as i32 - region.top as i32 + 1).max(0) as u32;
+    (width, height)
+}
+
+/**
+ * Check that the configured area has its edges in order, the images can not be empty
+ */
+fn validate_region(region: &TileRegion, setting: &str) {
+    if region.left > region.right || region.top > region.bottom {
+        panic!("Area of {} is empty, left needs to be at most right and top at most bottom: {}", setting, region);
+    }
+}
+
+/**
+ * Replay the edits made at or before the given time to the tiles of the region
+ */
+pub(crate) fn replay(tile_data: &TileData, region: &TileRegion, time: Option<PrimitiveDateTime>) -> CanvasState {
+    tile_data.par_fold(|| {
+        CanvasState::new(region.clone())
+    }, |mut state, position, row| {
+        if time.is_none_or(|t| row.timestamp <= t) && row.coordinate.intersects(region) {
+            state.apply(position, row);
+        }
+        state
+    }, CanvasState::merge)
+}
+
+/**
+ * Draw the canvas state, tiles nobody has edited are drawn with the background colour
+ */
+pub(crate) fn draw(state: &CanvasState) -> RgbImage {
+    let region = state.bounds();
+    let (width, height) = region_size(region);
+    let mut image = RgbImage::new(width, height, BACKGROUND);
+    for (tile, pixel) in state.pixels() {
+        image.set((tile.x as i32 - region.left as i32) as u32, (tile.y as i32 - region.top as i32) as u32, pixel.colour);
+    }
+    image
+}
+
+/**
+ * Image of the canvas at a moment
+ */
+#[derive(Deserialize)]
+pub(crate) struct Render {
+    //Show edits made at or before this time, defaults to the end of the event
+    #[serde(with = "rplace_time_format::option", default)]
+    time: Option<PrimitiveDateTime>,
+    //Part of the canvas to show, defaults to the whole canvas
+    area: Option<TileRegion>,
+    //Size of a tile in pixels
+    #[serde(default = "default_scale")]
+    scale: u32,
+    output: String,
+}
+
+/**
+ * Render the canvas as it was at the given time to a PNG file
+ */
+pub(crate) fn render_canvas(tile_data: &TileData, settings: &Render, canvas: &TileRegion) {
+    let region = settings.area.clone().unwrap_or_else(|| canvas.clone());
+    validate_region(&region, "render");
+    info!("Rendering {} to {}", region, settings.output);
+    let state = replay(tile_data, &region, settings.time);
+    draw(&state).scaled(settings.scale).save(&settings.output)
+        .unwrap_or_else(|e| panic!("{}", e));
+}
+
+/**
+ * Image of the final canvas with the tiles of the user highlighted
+ */
+#[derive(Deserialize)]
+pub(crate) struct Highlight {
+    //Location of the PNG file, with multiple users the index of the user is added to the name
+    output: String,
+    //Part of the canvas to show, defaults to the whole canvas
+    area: Option<TileRegion>,
+    //Size of a tile in pixels, tiles that survived to the end are outlined when it is at least 3
+    #[serde(default = "default_highlight_scale")]
+    scale: u32,
+}
+
+fn default_highlight_scale() -> u32 {
+    4
+}
+
+impl Highlight {
+    pub(crate) fn region(&self, canvas: &TileRegion) -> TileRegion {
+        let region = self.area.clone().unwrap_or_else(|| canvas.clone());
+        validate_region(&region, "highlight");
+        region
+    }
+
+    /**
+     * Location of the image of the user with the given index
+     */
+    fn output(&self, index: Option<usize>) -> String {
+        let index = match index {
+            Some(i) => { i }
+            None => { return self.output.clone(); }
+        };
+        match self.output.rsplit_once('.') {
+            Some((stem, extension)) if !extension.contains(['/', '\\']) => {
+                format!("{}_{}.{}", stem, index, extension)
+            }
+            _ => { format!("{}_{}", self.output, index) }
+        }
+    }
+}
+
+/**
+ * Colour of the outline of the tiles that survived to the end
+ */
+const OUTLINE: [u8; 3] = [255, 0, 255];
+
+/**
+ * Mix the colour with white so highlighted tiles stand out
+ */
+fn dim(colour: [u8; 3]) -> [u8; 3] {
+    colour.map(|c| ((c as u32 + 3 * 255) / 4) as u8)
+}
+
+/**
+ * Draw the final image with the tiles of the user that made it to the final image in their colour,
+ * other tiles are dimmed. Tiles of the user that survived to the end are outlined, they keep the colour
+ * of the final image so the image still shows the final image
+ */
+pub(crate) fn highlight_user(final_image: &CanvasState, end: &CanvasState, user: UserId, settings: &Highlight,
+                             canvas: &TileRegion, index: Option<usize>) {
+    let region = settings.region(canvas);
+    let output = settings.output(index);
+    info!("Highlighting tiles of the user in {} to {}", region, output);
+    let (width, height) = region_size(&region);
+    let mut image = RgbImage::new(width, height, dim(BACKGROUND));
+    let mut survived = Vec::new();
+    for tile in region.points() {
+        let (x, y) = ((tile.x as i32 - region.left as i32) as u32, (tile.y as i32 - region.top as i32) as u32);
+        if let Some(pixel) = final_image.pixel(tile.x, tile.y) {
+            let colour = if pixel.writer == user { pixel.colour } else { dim(pixel.colour) };
+            image.set(x, y, colour);
+        }
+        if end.pixel(tile.x, tile.y).is_some_and(|pixel| pixel.writer == user) {
+            survived.push((x, y));
+        }
+    }
+
+    let scale = settings.scale.max(1);
+    let mut image = image.scaled(scale);
+    if scale >= 3 {
+        for (x, y) in survived {
+            for i in 0..scale {
+                image.set(x * scale + i, y * scale, OUTLINE);
+                image.set(x * scale + i, y * scale + scale - 1, OUTLINE);
+                image.set(x * scale, y * scale + i, OUTLINE);
+                image.set(x * scale + scale - 1, y * scale + i, OUTLINE);
+            }
+        }
+    }
+    image.save(&output).unwrap_or_else(|e| panic!("{}", e));
+}
+
+/**
+ * Timelapse of a part of the canvas, frames can be written as numbered PNG files,
+ * as an animated PNG or both
+ */
+#[derive(Deserialize)]
+pub(crate) struct Timelapse {
+    //Part of the canvas to show, defaults to the whole canvas
+    area: Option<TileRegion>,
+    //Seconds of event time between frames
+    interval: u32,
+    //Time of the first frame, defaults to the time of the first edit
+    #[serde(with = "rplace_time_format::option", default)]
+    start_time: Option<PrimitiveDateTime>,
+    //Time of the last frame, defaults to the time of the last edit
+    #[serde(with = "rplace_time_format::option", default)]
+    end_time: Option<PrimitiveDateTime>,
+    //Size of a tile in pixels
+    #[serde(default = "default_scale")]
+    scale: u32,
+    //Start of the file names of the numbered frames
+    frames: Option<String>,
+    //Location of the animated PNG
+    animation: Option<String>,
+    //Time each frame of the animation is shown
+    #[serde(default = "default_frame_delay")]
+    frame_delay_ms: u16,
+}
+
+fn default_scale() -> u32 {
+    1
+}
+
+fn default_frame_delay() -> u16 {
+    100
+}
+
+/**
+ * Times of the timelapse frames, frames are every interval from the start until the first frame
+ * at or after the end, each frame shows the edits made at or before its time
+ */
+struct FrameTimes {
+    next: PrimitiveDateTime,
+    interval: Duration,
+    taken: u32,
+    //Number of frames, not known before the data is read when the end is not known
+    count: Option<u32>,
+}
+
+impl FrameTimes {
+    fn new(start: PrimitiveDateTime, end: Option<PrimitiveDateTime>, interval: Duration) -> FrameTimes {
+        let mut frame_times = FrameTimes {
+            next: start,
+            interval,
+            taken: 0,
+            count: None,
+        };
+        if let Some(end) = end {
+            frame_times.end_at(end);
+        }
+        frame_times
+    }
+
+    /**
+     * Set the end of the timelapse, frames already taken are counted
+     */
+    fn end_at(&mut self, end: PrimitiveDateTime) {
+        let start = self.next - self.interval * self.taken;
+        let count = if end < start {
+            0
+        } else {
+            let span = (end - start).whole_milliseconds();
+            let interval = self.interval.whole_milliseconds();
+            (span + interval - 1) / interval + 1
+        };
+        self.count = Some(u32::try_from(count).expect("Timelapse has too many frames"));
+    }
+
+    /**
+     * Number of frames not taken yet, None if the end is not known
+     */
+    fn remaining(&self) -> Option<u32> {
+        self.count.map(|c| c.saturating_sub(self.taken))
+    }
+
+    /**
+     * Take the time of the next frame if the frame is before the given edit time, or any remaining frame
+     * when there are no more edits
+     */
+    fn next_before(&mut self, time: Option<PrimitiveDateTime>) -> Option<PrimitiveDateTime> {
+        if self.remaining() == Some(0) || time.is_some_and(|t| t <= self.next) {
+            return None;
+        }
+        if time.is_none() && self.count.is_none() {
+            panic!("End of the timelapse is needed for the remaining frames");
+        }
+        let frame_time = self.next;
+        self.next += self.interval;
+        self.taken += 1;
+        Some(frame_time)
+    }
+}
+
+/**
+ * Frames of the timelapse that are written as they are drawn
+ */
+struct FrameWriter<'a> {
+    settings: &'a Timelapse,
+    animation: Option<AnimationWriter>,
+    count: usize,
+}
+
+impl FrameWriter<'_> {
+    fn add(&mut self, state: &CanvasState, time: PrimitiveDateTime) {
+        let frame = draw(state).scaled(self.settings.scale);
+        if let Some(prefix) = &self.settings.frames {
+            let path = format!("{}{:05}.png", prefix, self.count);
+            frame.save(&path).unwrap_or_else(|e| panic!("{}", e));
+        }
+        info!("Timelapse frame {} at {}", self.count, format_timestamp(&time));
+        if let Some(animation) = &mut self.animation {
+            animation.add(&frame).unwrap_or_else(|e| panic!("{}", e));
+        }
+        self.count += 1;
+    }
+}
+
+/**
+ * Replay the edits in the order of the data and draw the area every interval of event time
+ */
+pub(crate) fn timelapse(tile_data: &TileData, settings: &Timelapse, canvas: &TileRegion) {
+    if settings.interval == 0 {
+        panic!("Timelapse interval needs to be at least one second");
+    }
+    if settings.frames.is_none() && settings.animation.is_none() {
+        warn!("Timelapse has no frames or animation set, nothing is written");
+        return;
+    }
+    let region = settings.area.clone().unwrap_or_else(|| canvas.clone());
+    validate_region(&region, "timelapse");
+    let interval = Duration::seconds(settings.interval as i64);
+
+    let start = match settings.start_time.or_else(|| tile_data.first_edit_time()) {
+        Some(v) => { v }
+        None => {
+            warn!("Timelapse has no edits, nothing is written");
+            return;
+        }
+    };
+    //Last edit of compressed data is only known after it has been read
+    let end = settings.end_time.or_else(|| tile_data.last_edit_time());
+    let mut frame_times = FrameTimes::new(start, end, interval);
+    match frame_times.remaining() {
+        Some(0) => {
+            warn!("Timelapse ends before it starts, nothing is written");
+            return;
+        }
+        Some(count) => { info!("Creating timelapse of {} with {} frames", region, count); }
+        None => { info!("Creating timelapse of {}", region); }
+    }
+
+    //Number of frames is needed before the first frame of the animation is written
+    let (width, height) = region_size(&region);
+    let scale = settings.scale.max(1);
+    let animation = settings.animation.as_ref().map(|path| {
+        let count = frame_times.remaining()
+            .unwrap_or_else(|| panic!("Timelapse animation needs end_time when the tile data is compressed"));
+        AnimationWriter::create(path, width * scale, height * scale, count, settings.frame_delay_ms)
+            .unwrap_or_else(|e| panic!("{}", e))
+    });
+    let mut state = CanvasState::new(region.clone());
+    let mut writer = FrameWriter {
+        settings,
+        animation,
+        count: 0,
+    };
+    let mut position = 0;
+    let mut last_time = start;
+    tile_data.for_each(|row| {
+        position += 1;
+        if end.is_some_and(|t| row.timestamp > t) {
+            return;
+        }
+        last_time = last_time.max(row.timestamp);
+        while let Some(frame_time) = frame_times.next_before(Some(row.timestamp)) {
+            writer.add(&state, frame_time);
+        }
+        if row.coordinate.intersects(&region) {
+            state.apply(position, row);
+        }
+    });
+
+    //Frames until the end is shown
+    if end.is_none() {
+        frame_times.end_at(last_time);
+    }
+    while let Some(frame_time) = frame_times.next_before(None) {
+        writer.add(&state, frame_time);
+    }
+    if let (Some(animation), Some(path)) = (writer.animation, &settings.animation) {
+        info!("Wrote {} frames to {}", writer.count, path);
+        animation.finish().unwrap_or_else(|e| panic!("{}", e));
+    }
+}
+
+/**
+ * Number of edits to every tile of an area by the selected users
+ */
+#[derive(Deserialize)]
+pub(crate) struct Heatmap {
+    output: String,
+    //Hashed user ids of the users whose edits are counted, edits of everyone are counted when empty
+    #[serde(default)]
+    users: Vec<String>,
+    //Edits that are counted and the tiles shown, defaults to every edit of the whole canvas
+    search_area: Option<SearchArea>,
+    //Size of a tile in pixels
+    #[serde(default = "default_scale")]
+    scale: u32,
+}
+
+/**
+ * Colour of the heat between 0 and 1, black through red and yellow to white
+ */
+fn heat_colour(heat: f64) -> [u8; 3] {
+    let channel = |v: f64| (v.clamp(0.0, 1.0) * 255.0).round() as u8;
+    let heat = heat * 3.0;
+    [channel(heat), channel(heat - 1.0), channel(heat - 2.0)]
+}
+
+/**
+ * Count the edits to every tile of the area and write them as a PNG, counts are shown on a logarithmic
+ * scale so that tiles with few edits are visible next to the most contested ones
+ */
+pub(crate) fn heatmap(tile_data: &TileData, settings: &Heatmap, canvas: &TileRegion) {
+    let search_area = settings.search_area.as_ref();
+    let region = search_area.map(|a| a.area.bounds().clone()).unwrap_or_else(|| canvas.clone());
+    validate_region(&region, "heatmap");
+    let (width, height) = region_size(&region);
+    let users: HashSet<UserId> = settings.users.iter().map(|u| tile_data.users().intern(u)).collect();
+    info!("Counting edits in {} for {}", region, settings.output);
+
+    let counts = tile_data.par_fold(|| {
+        vec![0u32; width as usize * height as usize]
+    }, |mut counts, _, row| {
+        if !users.is_empty() && !users.contains(&row.user_id) {
+            return counts;
+        }
+        if !search_area.map_or_else(|| row.coordinate.intersects(&region), |a| a.contains(row)) {
+            return counts;
+        }
+        row.coordinate.for_each_point(|tile| {
+            if region.contains(&tile) && search_area.is_none_or(|a| a.area.contains(&tile)) {
+                let i = (tile.y as i32 - region.top as i32) as usize * width as usize + (tile.x as i32 - region.left as i32) as usize;
+                counts[i] += 1;
+            }
+        });
+        counts
+    }, |mut a, b| {
+        a.iter_mut().zip(b).for_each(|(a, b)| *a += b);
+        a
+    });
+
+    let max = counts.iter().copied().max().unwrap_or(0);
+    info!("Most edited tile has {} edits", max);
+    let scale = f64::ln(1.0 + max as f64);
+    let mut image = RgbImage::new(width, height, heat_colour(0.0));
+    for (i, count) in counts.iter().enumerate() {
+        if *count > 0 {
+            let heat = f64::ln(1.0 + *count as f64) / scale;
+            image.set(i as u32 % width, i as u32 / width, heat_colour(heat));
+        }
+    }
+    image.scaled(settings.scale).save(&settings.output)
+        .unwrap_or_else(|e| panic!("{}", e));
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::input::{TempFile, write_csv};
+    use crate::PlaceDataSet;
+    use crate::users::UserTable;
+    use super::*;
+
+    fn region(left: i16, top: i16, right: i16, bottom: i16) -> TileRegion {
+        TileRegion {
+            left,
+            top,
+            right,
+            bottom,
+        }
+    }
+
+    fn pixel(image: &RgbImage, x: u32, y: u32) -> [u8; 3] {
+        let i = (y as usize * image.width as usize + x as usize) * 3;
+        [image.pixels[i], image.pixels[i + 1], image.pixels[i + 2]]
+    }
+
+    #[test]
+    fn draw_replayed_canvas() {
+        let csv = write_csv("render", "\
+timestamp,user_id,pixel_color,coordinate
+2022-04-01 13:00:00.000 UTC,A==,#FF0000,\"1,1\"
+2022-04-01 13:00:01.000 UTC,B==,#0000FF,\"2,1,3,2\"
+2022-04-01 13:00:02.000 UTC,A==,#000000,\"3,2\"
+2022-04-01 13:00:03.000 UTC,B==,#00FF00,\"1,1\"
+2022-04-01 13:00:04.000 UTC,B==,#00FF00,\"9,9\"
+");
+        let tile_data = TileData::from_csv(vec![csv.path.clone()], PlaceDataSet::Place2022);
+        let area = region(1, 1, 3, 2);
+        let before = draw(&replay(&tile_data, &area, Some(time::macros::datetime!(2022-04-01 13:00:02))));
+        let end = draw(&replay(&tile_data, &area, None));
+
+        //Edits at the time are included, later edits and tiles outside of the area are not
+        assert_eq!((before.width, before.height), (3, 2));
+        assert_eq!(pixel(&before, 0, 0), [255, 0, 0]);
+        assert_eq!(pixel(&before, 1, 0), [0, 0, 255]);
+        assert_eq!(pixel(&before, 2, 0), [0, 0, 255]);
+        assert_eq!(pixel(&before, 0, 1), BACKGROUND);
+        assert_eq!(pixel(&before, 1, 1), [0, 0, 255]);
+        assert_eq!(pixel(&before, 2, 1), [0, 0, 0]);
+        assert_eq!(pixel(&end, 0, 0), [0, 255, 0]);
+        assert_eq!(pixel(&end, 2, 1), [0, 0, 0]);
+    }
+
+    #[test]
+    fn scaled_image_repeats_pixels() {
+        let mut image = RgbImage::new(2, 1, BACKGROUND);
+        image.set(1, 0, [1, 2, 3]);
+        let scaled = image.scaled(3);
+        assert_eq!((scaled.width, scaled.height), (6, 3));
+        for y in 0..3 {
+            for x in 0..6 {
+                let expected = if x < 3 { BACKGROUND } else { [1, 2, 3] };
+                assert_eq!(pixel(&scaled, x, y), expected, "{}, {}", x, y);
+            }
+        }
+        assert_eq!(image.scaled(1).pixels, image.pixels);
+    }
+
+    #[test]
+    #[should_panic(expected = "Area of render is empty")]
+    fn render_rejects_reversed_area() {
+        let settings = Render {
+            time: None,
+            area: Some(region(5, 0, 4, 10)),
+            scale: 1,
+            output: "unused.png".to_string(),
+        };
+        let tile_data = TileData::from_csv(Vec::new(), PlaceDataSet::Place2022);
+        render_canvas(&tile_data, &settings, &PlaceDataSet::Place2022.canvas());
+    }
+
+    #[test]
+    fn frame_times_cover_the_end() {
+        let start = time::macros::datetime!(2022-04-01 13:00:00);
+        let end = time::macros::datetime!(2022-04-01 13:00:25);
+        //End is known before reading or only after the last edit has been read
+        for known_end in [Some(end), None] {
+            let mut frames = FrameTimes::new(start, known_end, Duration::seconds(10));
+            assert_eq!(frames.remaining(), known_end.map(|_| 4));
+            //Frame is taken when the first edit after it is seen
+            let mut emitted = Vec::new();
+            for second in [0, 5, 10, 10, 25] {
+                let row_time = start + Duration::seconds(second);
+                while let Some(frame_time) = frames.next_before(Some(row_time)) {
+                    emitted.push((second, (frame_time - start).whole_seconds()));
+                }
+            }
+            assert_eq!(emitted, vec![(5, 0), (25, 10), (25, 20)]);
+            if known_end.is_none() {
+                frames.end_at(end);
+            }
+            assert_eq!(frames.remaining(), Some(1));
+            assert_eq!(frames.next_before(None), Some(start + Duration::seconds(30)));
+            assert_eq!(frames.next_before(None), None);
+        }
+
+        let mut frames = FrameTimes::new(start, Some(start), Duration::seconds(10));
+        assert_eq!(frames.next_before(None), Some(start));
+        assert_eq!(frames.next_before(None), None);
+        assert_eq!(FrameTimes::new(start, Some(start - Duration::seconds(1)), Duration::seconds(10)).remaining(), Some(0));
+    }
+
+    #[test]
+    fn heatmap_counts_edits_in_search_area() {
+        let output = TempFile::new("heatmap", "png");
+        let csv = write_csv("heatmap", "\
+timestamp,user_id,pixel_color,coordinate
+2022-04-01 13:00:00.000 UTC,A==,#FF0000,\"0,0\"
+2022-04-01 13:00:01.000 UTC,A==,#FF0000,\"0,0\"
+2022-04-01 13:00:02.000 UTC,B==,#0000FF,\"1,0\"
+2022-04-01 13:00:03.000 UTC,A==,#FF0000,\"0,0\"
+2022-04-01 13:00:04.000 UTC,A==,#FF0000,\"2,2\"
+2022-04-01 13:00:05.000 UTC,B==,#0000FF,\"0,1,2,2\"
+2022-04-01 13:00:06.000 UTC,A==,#FF0000,\"1,0\"
+");
+        let settings: Heatmap = config::Config::builder()
+            .add_source(config::File::from_str(&format!("
+output = \"{}\"
+[search_area]
+start_time = \"2022-04-01 13:00:01.000 UTC\"
+end_time = \"2022-04-01 13:00:05.000 UTC\"
+area = {{ polygon = [[0, 0], [2, 0], [0, 2]] }}
+", output.path.display()), config::FileFormat::Toml))
+            .build()
+            .and_then(|c| c.try_deserialize())
+            .expect("Valid heatmap settings");
+        let tile_data = TileData::from_csv(vec![csv.path.clone()], PlaceDataSet::Place2022);
+        heatmap(&tile_data, &settings, &PlaceDataSet::Place2022.canvas());
+        let image = crate::area::Image::load(&output.path.display().to_string()).expect("Heatmap is written");
+
+        //Image covers the bounds of the triangle, edits outside of its time or tiles are not counted
+        assert_eq!((image.width, image.height), (3, 3));
+        let once = heat_colour(f64::ln(2.0) / f64::ln(3.0));
+        let expected = [
+            [heat_colour(1.0), once, heat_colour(0.0)],
+            [once, once, heat_colour(0.0)],
+            [once, heat_colour(0.0), heat_colour(0.0)],
+        ];
+        for (y, row) in expected.iter().enumerate() {
+            for (x, colour) in row.iter().enumerate() {
+                assert_eq!(image.pixels[y * 3 + x], Some(*colour), "{}, {}", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn highlight_keeps_final_image() {
+        let users = UserTable::new();
+        let final_image_time = time::macros::datetime!(2022-04-01 13:00:05);
+        let area = region(0, 0, 3, 1);
+        let mut final_image = CanvasState::new(area.clone());
+        let mut end = CanvasState::new(area.clone());
+        let rows = [
+            "2022-04-01 13:00:00.000 UTC,A==,#FF4500,\"0,0\"",
+            "2022-04-01 13:00:01.000 UTC,B==,#000000,\"1,0\"",
+            "2022-04-01 13:00:02.000 UTC,A==,#0000FF,\"2,0\"",
+            //Whiteout
+            "2022-04-01 13:00:06.000 UTC,B==,#FFFFFF,\"0,0\"",
+            "2022-04-01 13:00:07.000 UTC,A==,#00FF00,\"3,0\"",
+        ];
+        for (position, line) in rows.iter().enumerate() {
+            let (_, row) = PlaceDataSet::Place2022.parse_line(line, &users).expect("Valid test row");
+            if row.timestamp < final_image_time {
+                final_image.apply(position as u64, &row);
+            }
+            end.apply(position as u64, &row);
+        }
+        let output = TempFile::new("highlight", "png");
+        //Index of the user is added to the name of the image
+        let indexed = TempFile {
+            path: output.path.with_file_name(format!("{}_1.png", output.path.file_stem().and_then(|s| s.to_str()).expect("Test file name"))),
+        };
+        let settings = Highlight {
+            output: output.path.display().to_string(),
+            area: None,
+            scale: 3,
+        };
+        highlight_user(&final_image, &end, users.intern("A=="), &settings, &area, Some(1));
+        assert!(!output.path.exists());
+        let image = crate::area::Image::load(&indexed.path.display().to_string()).expect("Highlight is written");
+        assert_eq!((image.width, image.height), (12, 6));
+        let at = |x: usize, y: usize| image.pixels[y * image.width + x].expect("Opaque pixel");
+
+        //Tile of the user painted over in the whiteout keeps its colour of the final image and is not outlined
+        assert_eq!(at(1, 1), [255, 69, 0]);
+        assert_eq!(at(0, 0), [255, 69, 0]);
+        //Tile of other user is dimmed
+        assert_eq!(at(4, 1), dim([0, 0, 0]));
+        //Tile of the user that survived to the end is outlined
+        assert_eq!(at(7, 1), [0, 0, 255]);
+        assert_eq!(at(6, 0), OUTLINE);
+        assert_eq!(at(8, 2), OUTLINE);
+        //Tile placed during the whiteout is outlined but shows the final image
+        assert_eq!(at(10, 1), dim(BACKGROUND));
+        assert_eq!(at(9, 1), OUTLINE);
+        assert_eq!(at(1, 4), dim(BACKGROUND));
+
+        assert_eq!(settings.output(None), settings.output);
+        let settings = Highlight {
+            output: "images.v2/highlight".to_string(),
+            area: None,
+            scale: 3,
+        };
+        assert_eq!(settings.output(Some(0)), "images.v2/highlight_0");
+    }
+}
@@ -0,0 +1,117 @@
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex, RwLock};
+
+/**
+ * Interned user id, index to the user table
+ */
+#[derive(Eq, PartialEq, Hash, Clone, Copy, Debug, PartialOrd, Ord)]
+pub(crate) struct UserId(pub(crate) u32);
+
+/**
+ * Number of separately locked parts of the table, lets parsing threads intern users without
+ * waiting for each other
+ */
+const USER_SHARDS: usize = 64;
+
+/**
+ * Table mapping the hashed user ids of the canvas history to dense integer ids
+ */
+pub(crate) struct UserTable {
+    shards: Vec<Mutex<HashMap<Arc<str>, UserId>>>,
+    names: RwLock<Vec<Arc<str>>>,
+}
+
+impl UserTable {
+    pub(crate) fn new() -> UserTable {
+        UserTable {
+            shards: (0..USER_SHARDS).map(|_| Mutex::new(HashMap::new())).collect(),
+            names: RwLock::new(Vec::new()),
+        }
+    }
+
+    /**
+     * Create table where the user ids are the indexes of the names
+     */
+    pub(crate) fn from_names(names: Vec<String>) -> UserTable {
+        let table = UserTable::new();
+        for name in names {
+            table.intern(&name);
+        }
+        table
+    }
+
+    fn shard(&self, name: &str) -> &Mutex<HashMap<Arc<str>, UserId>> {
+        let mut hasher = DefaultHasher::new();
+        name.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % USER_SHARDS]
+    }
+
+    /**
+     * Get the id of the user, users not yet in the table are given the next free id
+     */
+    pub(crate) fn intern(&self, name: &str) -> UserId {
+        let mut shard = self.shard(name).lock().expect("User table lock poisoned");
+        if let Some(id) = shard.get(name) {
+            return *id;
+        }
+        let name: Arc<str> = Arc::from(name);
+        let id = {
+            let mut names = self.names.write().expect("User table lock poisoned");
+            let id = UserId(u32::try_from(names.len()).expect("Too many users for the user table"));
+            names.push(name.clone());
+            id
+        };
+        shard.insert(name, id);
+        id
+    }
+
+    /**
+     * Get the hashed user id of the user
+     */
+    pub(crate) fn name(&self, id: UserId) -> Arc<str> {
+        let names = self.names.read().expect("User table lock poisoned");
+        names[id.0 as usize].clone()
+    }
+
+    /**
+     * Get the hashed user ids ordered by user id
+     */
+    pub(crate) fn names(&self) -> Vec<Arc<str>> {
+        self.names.read().expect("User table lock poisoned").clone()
+    }
+}
+
+/**
+ * Count for every user stored by user id, users without a count have zero
+ */
+#[derive(Default)]
+pub(crate) struct UserCounts {
+    counts: Vec<u32>,
+}
+
+impl UserCounts {
+    pub(crate) fn add(&mut self, user: UserId) {
+        let index = user.0 as usize;
+        if index >= self.counts.len() {
+            self.counts.resize(index + 1, 0);
+        }
+        self.counts[index] += 1;
+    }
+
+    pub(crate) fn get(&self, user: UserId) -> u64 {
+        self.counts.get(user.0 as usize).copied().unwrap_or(0) as u64
+    }
+
+    /**
+     * Add the counts of the other users to these, the shorter array is added to the longer one
+     */
+    pub(crate) fn merge(mut self, mut other: UserCounts) -> UserCounts {
+        if self.counts.len() < other.counts.len() {
+            std::mem::swap(&mut self.counts, &mut other.counts);
+        }
+        self.counts.iter_mut().zip(other.counts).for_each(|(a, b)| *a += b);
+        self
+    }
+}